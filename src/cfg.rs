@@ -1,198 +1,393 @@
-use std::fmt::{self, Display};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display},
+};
 
-use crate::{lvn::LVN, parser::{Bril, Function, Instr, Opcode}, utils::{bril2json, bril2txt}};
+use crate::{error::{Error, Result}, parser::{Arg, Bril, Function, Instr, Opcode, Type}};
 
 pub struct BrilCFG {
     bril: Bril,
-    unamed_block_cnt: usize,
-    cur_name: Option<String>,
+    pub functions: Vec<FunctionCFG>,
+}
+
+pub type BlockId = usize;
+
+// the cfg of a single function. block 0 is the entry block, it is synthesized
+// by the parser and never printed with a label
+pub struct FunctionCFG {
+    pub name: String,
+    pub args: Option<Vec<Arg>>,
+    pub typ: Option<Type>,
     pub blocks: Vec<Block>,
+    names: HashMap<String, BlockId>,
+    unamed_block_cnt: usize,
 }
 
 pub struct Block {
-    name: String,
+    pub(crate) name: String,
     pub(crate) instrs: Vec<Instr>,
-    succ: Option<Vec<String>>,
-    func: String,
-    pub(crate) lvn: Option<LVN>
+    succs: Vec<BlockId>,
+    preds: Vec<BlockId>,
 }
 
 const TERMINATOR: [Opcode; 3] = [Opcode::jmp, Opcode::ret, Opcode::br];
 
 impl Display for Block {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "{}:", self.name).unwrap();
+        writeln!(f, "{}:", self.name)?;
         for instr in &self.instrs {
-            writeln!(f, "\t{:?}:", instr).unwrap();
+            writeln!(f, "  {instr}")?;
+        }
+        writeln!(f, "preds: {:?}", self.preds)?;
+        writeln!(f, "succs: {:?}", self.succs)
+    }
+}
+
+impl Display for FunctionCFG {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "@{}", self.name)?;
+        for (id, block) in self.blocks.iter().enumerate() {
+            write!(f, "{id}: {block}")?;
         }
-        writeln!(f, "succ: {:?}", self.succ).unwrap();
         Ok(())
     }
 }
 
 impl Block {
-    pub fn new(name: String, instrs: Vec<Instr>, func: String) -> Self {
+    pub fn new(name: String, instrs: Vec<Instr>) -> Self {
         Self {
             name,
             instrs,
-            succ: None,
-            func,
-            lvn: None
+            succs: vec![],
+            preds: vec![],
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn instrs(&self) -> &[Instr] {
+        &self.instrs
+    }
+
+    pub fn succs(&self) -> &[BlockId] {
+        &self.succs
+    }
+
+    pub fn preds(&self) -> &[BlockId] {
+        &self.preds
+    }
 }
 
 impl BrilCFG {
     pub fn new(bril: Bril) -> Self {
         Self {
             bril,
-            unamed_block_cnt: 0,
-            cur_name: None,
-            blocks: vec![],
+            functions: vec![],
         }
     }
-    pub fn from_text(text: &str) -> Self {
-        let bril_json = bril2json(text);
-
-        let bril: Bril = serde_json::from_str(&bril_json).unwrap();
+    pub fn from_text(text: &str) -> Result<Self> {
+        let bril = Bril::from_text(text)?;
+        let mut cfg = BrilCFG::new(bril);
+        cfg.parse_blocks()?;
+        Ok(cfg)
+    }
+    pub fn from_json(json: &str) -> Result<Self> {
+        let bril: Bril = serde_json::from_str(json)?;
         let mut cfg = BrilCFG::new(bril);
-        cfg.parse_blocks();
-        cfg
+        cfg.parse_blocks()?;
+        Ok(cfg)
     }
     pub fn to_text(&self) -> String {
-        let bril = self.to_bril();
-        let bril_json = serde_json::to_string(&bril).expect("cannot convert bril {bril:?}");
-        bril2txt(&bril_json)
+        self.to_bril().to_string()
     }
-    pub fn resolve_cfg(&mut self) {
-        for (cnt, block) in self.blocks.iter().enumerate() {
-            if let Some(last) = block.instrs.last() {
-                use crate::parser::Instr::*;
-                let mut succ = None;
-                if let Instruction { op, labels, .. } = last {
-                    if [Opcode::jmp, Opcode::br].contains(op) {
-                        let labels = labels.as_ref().unwrap();
-                        succ = Some(labels.clone())
-                    } else if op == &Opcode::ret {
-                        succ = None
-                    } else {
-                        // get next block
-                        if let Some(next_block) = self.blocks.get(cnt + 1) {
-                            succ = Some(vec![next_block.name.clone()]);
-                        } else {
-                            succ = None;
-                        }
+    pub fn parse_blocks(&mut self) -> Result<()> {
+        for func in std::mem::take(&mut self.bril.functions) {
+            self.functions.push(FunctionCFG::new(func)?);
+        }
+        Ok(())
+    }
+
+    pub fn to_bril(&self) -> Bril {
+        Bril {
+            functions: self.functions.iter().map(FunctionCFG::to_function).collect(),
+        }
+    }
+
+    pub fn function(&self, name: &str) -> Option<&FunctionCFG> {
+        self.functions.iter().find(|func| func.name == name)
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item = &Block> {
+        self.functions.iter().flat_map(|func| func.blocks.iter())
+    }
+
+    pub fn blocks_mut(&mut self) -> impl Iterator<Item = &mut Block> {
+        self.functions.iter_mut().flat_map(|func| func.blocks.iter_mut())
+    }
+}
+
+impl FunctionCFG {
+    pub fn new(func: Function) -> Result<Self> {
+        let Function { name, args, typ, instrs } = func;
+        let mut cfg = Self {
+            name,
+            args,
+            typ,
+            blocks: vec![],
+            names: HashMap::new(),
+            unamed_block_cnt: 0,
+        };
+        // labels of the function, so synthesized names never collide with them
+        let labels = instrs
+            .iter()
+            .filter_map(|instr| match instr {
+                Instr::Label { label } => Some(label.clone()),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+        for label in &labels {
+            cfg.names.insert(label.clone(), BlockId::MAX);
+        }
+
+        let entry = cfg.name.clone();
+        cfg.push_block(entry, vec![]);
+        let mut cur_name = None;
+        let mut block_instrs = vec![];
+        for instr in instrs {
+            match instr {
+                Instr::Instruction { ref op, .. } => {
+                    let is_terminator = TERMINATOR.contains(op);
+                    block_instrs.push(instr);
+                    if is_terminator {
+                        cfg.close_block(cur_name.take(), std::mem::take(&mut block_instrs));
                     }
-                    let ptr = self.blocks.as_ptr() as *mut Block;
-                    unsafe {
-                        // cur block will never be used afterwards, so it's safe to
-                        // change current block
-                        // so unsafe code is applied to bypass the dumb checking system
-                        (*ptr.add(cnt)).succ = succ.clone();
+                }
+                Instr::Label { label } => {
+                    // nothing but a terminator before this label, no block to close
+                    if !block_instrs.is_empty() || cur_name.is_some() {
+                        cfg.close_block(cur_name.take(), std::mem::take(&mut block_instrs));
                     }
-                } else {
-                    panic!("unexpected label instruction");
+                    cur_name = Some(label);
                 }
-            } else {
-                panic!("unexpected empty block");
             }
         }
+        if !block_instrs.is_empty() || cur_name.is_some() {
+            cfg.close_block(cur_name, block_instrs);
+        }
+        cfg.resolve_cfg()?;
+        Ok(cfg)
     }
-    pub fn parse_blocks(&mut self) {
-        let mut instrs = vec![];
-        let mut cur_func_name = "".to_string();
-        for func in self.bril.functions.clone() {
-            cur_func_name = func.name.clone();
-            self.set_cur_block_name(&cur_func_name);
-            for instr in &func.instrs {
-                use crate::parser::Instr::*;
-                match instr {
-                    Instruction { op, .. } => {
-                        instrs.push(instr.clone());
-                        if TERMINATOR.contains(op) {
-                            let block = Block::new(self.cur_block_name(), instrs.clone(), cur_func_name.clone());
-                            self.blocks.push(block);
-                            instrs.clear();
-                        }
-                    }
-                    Label { label } => {
-                        let block = Block::new(self.cur_block_name(), instrs.clone(), cur_func_name.clone());
-                        self.blocks.push(block);
-                        instrs.clear();
-                        self.set_cur_block_name(label);
-                    }
-                }
+
+    fn close_block(&mut self, name: Option<String>, instrs: Vec<Instr>) {
+        // instructions before the first label belong to the entry block
+        if name.is_none() && self.blocks.len() == 1 && self.blocks[0].instrs.is_empty() {
+            self.blocks[0].instrs = instrs;
+            return;
+        }
+        let name = name.unwrap_or_else(|| self.fresh_block_name("tmp"));
+        self.push_block(name, instrs);
+    }
+
+    fn push_block(&mut self, name: String, instrs: Vec<Instr>) -> BlockId {
+        let id = self.blocks.len();
+        self.names.insert(name.clone(), id);
+        self.blocks.push(Block::new(name, instrs));
+        id
+    }
+
+    // a block name that is not used by any label or block of this function
+    pub fn fresh_block_name(&mut self, prefix: &str) -> String {
+        loop {
+            let name = format!("{prefix}{}", self.unamed_block_cnt);
+            self.unamed_block_cnt += 1;
+            if !self.names.contains_key(&name) {
+                return name;
             }
         }
-        if !instrs.is_empty() {
-            let block = Block::new(self.cur_block_name(), instrs.clone(), cur_func_name.clone());
-            self.blocks.push(block);
+    }
+
+    // append a block with a fresh name, reached only by explicit jumps.
+    // a last block that fell off the end of the function gets a `ret` so it
+    // doesn't fall into the new one. call `resolve_cfg` once the edges are in
+    pub fn add_block(&mut self, prefix: &str, instrs: Vec<Instr>) -> BlockId {
+        let name = self.fresh_block_name(prefix);
+        self.append_block(name, instrs)
+    }
+
+    // like `add_block`, for a name from `fresh_block_name` that jumps in other
+    // new blocks already refer to
+    pub fn append_block(&mut self, name: String, instrs: Vec<Instr>) -> BlockId {
+        if let Some(last) = self.blocks.last_mut() {
+            let terminated = matches!(last.instrs.last(), Some(Instr::Instruction { op, .. }) if TERMINATOR.contains(op));
+            if !terminated {
+                last.instrs.push(Instr::Instruction {
+                    op: Opcode::ret,
+                    dest: None,
+                    typ: None,
+                    args: None,
+                    funcs: None,
+                    labels: None,
+                    value: None,
+                });
+            }
         }
-        self.resolve_cfg();
+        self.push_block(name, instrs)
     }
 
-    // TODO: transform from cfg to original bril
-    pub fn to_bril(&self) -> Bril {
-        let mut cur_name = String::new();
-        let mut cur_func = None;
-        let mut funcs = vec![];
-        for block in &self.blocks {
-            if cur_name != block.func {
-                if let Some(func) = cur_func.take() {
-                    funcs.push(func);
+    // make the edge `from -> to` go to `target` instead, phis are left alone.
+    // call `resolve_cfg` afterwards
+    pub fn redirect_edge(&mut self, from: BlockId, to: BlockId, target: BlockId) {
+        let (old, new) = (self.blocks[to].name.clone(), self.blocks[target].name.clone());
+        let block = &mut self.blocks[from];
+        match block.instrs.last_mut() {
+            Some(Instr::Instruction { op: Opcode::jmp | Opcode::br, labels: Some(labels), .. }) => {
+                for label in labels.iter_mut().filter(|label| **label == old) {
+                    *label = new.clone();
                 }
-                cur_name = block.func.clone();
-                cur_func = self.get_func_by_name(&cur_name);
             }
-            cur_func = cur_func.map(|mut func| {
-                if block.name != cur_name {
-                    // add label
-                    let label = Instr::Label {
-                        label: block.name.clone(),
-                    };
-                    func.instrs.push(label);
-                }
-                func.instrs.append(&mut block.instrs.clone());
-                func
-            });
-        }
-        if let Some(cur_func) = cur_func {
-            funcs.push(cur_func);
+            // falls through
+            _ => block.instrs.push(Instr::Instruction {
+                op: Opcode::jmp,
+                dest: None,
+                typ: None,
+                args: None,
+                funcs: None,
+                labels: Some(vec![new]),
+                value: None,
+            }),
         }
-        Bril { functions: funcs }
     }
 
-    // the return function have empty instrs
-    fn get_func_by_name(&self, name: &str) -> Option<Function> {
-        for func in &self.bril.functions {
-            if func.name == name {
-                let mut res = func.clone();
-                res.instrs.clear();
-                return Some(res);
+    pub fn block_id(&self, name: &str) -> Option<BlockId> {
+        self.names.get(name).copied().filter(|id| *id < self.blocks.len())
+    }
+
+    pub fn entry(&self) -> BlockId {
+        0
+    }
+
+    // blocks that leave the function, by `ret` or by falling off the end
+    pub fn exits(&self) -> Vec<BlockId> {
+        (0..self.blocks.len())
+            .filter(|id| self.blocks[*id].succs.is_empty())
+            .collect()
+    }
+
+    // blocks reachable from the entry, each one after all its predecessors
+    // except along back edges
+    pub fn reverse_postorder(&self) -> Vec<BlockId> {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut postorder = Vec::with_capacity(self.blocks.len());
+        // (block, index of the next successor to visit)
+        let mut stack = vec![(self.entry(), 0)];
+        visited[self.entry()] = true;
+        while let Some((id, next)) = stack.last_mut() {
+            let id = *id;
+            if let Some(&succ) = self.blocks[id].succs.get(*next) {
+                *next += 1;
+                if !visited[succ] {
+                    visited[succ] = true;
+                    stack.push((succ, 0));
+                }
+            } else {
+                postorder.push(id);
+                stack.pop();
             }
         }
-        None
+        postorder.reverse();
+        postorder
     }
 
-    fn set_cur_block_name(&mut self, name: &str) {
-        self.cur_name = Some(name.to_string())
+    pub fn edges(&self) -> impl Iterator<Item = (BlockId, BlockId)> + '_ {
+        self.blocks
+            .iter()
+            .enumerate()
+            .flat_map(|(id, block)| block.succs.iter().map(move |succ| (id, *succ)))
     }
-    fn cur_block_name(&mut self) -> String {
-        if let Some(name) = self.cur_name.take() {
-            name
-        } else {
-            // FIXME: what if some label is start with tmp?
-            let tmp_name = format!("tmp{}", self.unamed_block_cnt);
-            self.unamed_block_cnt += 1;
-            tmp_name
+
+    // recompute succs and preds, needed after a pass changes terminators or
+    // adds and removes blocks
+    pub fn resolve_cfg(&mut self) -> Result<()> {
+        self.names = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(id, block)| (block.name.clone(), id))
+            .collect();
+        let mut succs = Vec::with_capacity(self.blocks.len());
+        for (cnt, block) in self.blocks.iter().enumerate() {
+            use crate::parser::Instr::*;
+            let succ = match block.instrs.last() {
+                Some(Instruction { op, labels, .. }) if [Opcode::jmp, Opcode::br].contains(op) => {
+                    let labels = labels.as_ref().ok_or_else(|| {
+                        Error::MalformedCfg(format!("`{op}` without target in block {}", block.name))
+                    })?;
+                    let mut succ = vec![];
+                    for label in labels {
+                        let id = self.block_id(label).ok_or_else(|| {
+                            Error::MalformedCfg(format!(
+                                "jump to undefined label `{label}` in function {}",
+                                self.name
+                            ))
+                        })?;
+                        // `br c .a .a` is a single edge
+                        if !succ.contains(&id) {
+                            succ.push(id);
+                        }
+                    }
+                    succ
+                }
+                Some(Instruction { op: Opcode::ret, .. }) => vec![],
+                Some(Label { label }) => {
+                    return Err(Error::MalformedCfg(format!(
+                        "unexpected label `{label}` inside block {}",
+                        block.name
+                    )));
+                }
+                // empty blocks (a label followed by a label) fall through as well,
+                // the last block falls off the end of the function
+                Some(Instruction { .. }) | None if cnt + 1 < self.blocks.len() => vec![cnt + 1],
+                Some(Instruction { .. }) | None => vec![],
+            };
+            succs.push(succ);
+        }
+        for block in self.blocks.iter_mut() {
+            block.preds.clear();
+        }
+        for (id, succ) in succs.iter().enumerate() {
+            for s in succ {
+                self.blocks[*s].preds.push(id);
+            }
+        }
+        for (block, succ) in self.blocks.iter_mut().zip(succs) {
+            block.succs = succ;
+        }
+        Ok(())
+    }
+
+    pub fn to_function(&self) -> Function {
+        let mut instrs = vec![];
+        for (id, block) in self.blocks.iter().enumerate() {
+            if id != self.entry() {
+                instrs.push(Instr::Label {
+                    label: block.name.clone(),
+                });
+            }
+            instrs.extend(block.instrs.iter().cloned());
+        }
+        Function {
+            name: self.name.clone(),
+            args: self.args.clone(),
+            typ: self.typ.clone(),
+            instrs,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::utils::bril2json;
-
     use super::*;
     // TODO: test on bril's test directory
 
@@ -206,13 +401,10 @@ mod tests {
         print v;
 }"#;
 
-        let bril_json = bril2json(bril_text);
-        println!("bril_json: {bril_json}");
-
-        let bril: Bril = serde_json::from_str(&bril_json).unwrap();
+        let bril = Bril::from_text(bril_text).unwrap();
         let mut cfg = BrilCFG::new(bril);
-        cfg.parse_blocks();
-        for block in &cfg.blocks {
+        cfg.parse_blocks().unwrap();
+        for block in cfg.blocks() {
             println!("{block}");
         }
 
@@ -223,4 +415,102 @@ mod tests {
             r#"{"functions":[{"name":"main","instrs":[{"op":"const","dest":"v","type":"int","value":4},{"op":"jmp","labels":["somewhere"]},{"label":"tmp0"},{"op":"const","dest":"v","type":"int","value":2},{"label":"somewhere"},{"op":"print","args":["v"]}]}]}"#
         );
     }
+
+    #[test]
+    fn cfg_errors() {
+        match BrilCFG::from_text("@main {\n  v: int = const;\n}") {
+            Err(Error::Parse(e)) => assert_eq!((e.line, e.col), (2, 17)),
+            _ => panic!("expected a parse error"),
+        }
+        match BrilCFG::from_json(r#"{"functions": [}"#) {
+            Err(Error::Parse(e)) => assert_eq!(e.line, 1),
+            _ => panic!("expected a parse error"),
+        }
+        match BrilCFG::from_text("@main { jmp .nowhere; }") {
+            Err(Error::MalformedCfg(msg)) => assert!(msg.contains("nowhere")),
+            _ => panic!("expected a malformed cfg error"),
+        }
+    }
+
+    #[test]
+    fn cfg_empty_blocks() {
+        let bril_text = r#"@main {
+.entry:
+.loop:
+  jmp .exit;
+.exit:
+  ret;
+}"#;
+        let cfg = BrilCFG::from_text(bril_text).unwrap();
+        assert_eq!(cfg.to_text(), Bril::from_text(bril_text).unwrap().to_string());
+    }
+
+    #[test]
+    fn cfg_per_function() {
+        let bril_text = r#"@main(n: int) {
+  jmp .end;
+  v: int = const 1;
+.end:
+  print n;
+}
+
+@other: int {
+  x: int = const 2;
+  jmp .end;
+.tmp0:
+  nop;
+.end:
+  ret x;
+}
+"#;
+        let cfg = BrilCFG::from_text(bril_text).unwrap();
+        assert_eq!(cfg.functions.len(), 2);
+        let main = cfg.function("main").unwrap();
+        assert_eq!(main.args.as_ref().unwrap()[0].name, "n");
+        // the last block of main falls off the end instead of into @other
+        let end = main.block_id("end").unwrap();
+        assert!(main.blocks[end].succs().is_empty());
+        assert_eq!(main.blocks[main.entry()].succs(), &[end]);
+
+        let other = cfg.function("other").unwrap();
+        assert_eq!(other.typ, Some(Type::int));
+        let end = other.block_id("end").unwrap();
+        assert_eq!(other.blocks[end].instrs.len(), 1);
+        // the user label tmp0 is not reused for a synthesized block
+        assert_eq!(other.blocks.iter().filter(|b| b.name == "tmp0").count(), 1);
+
+        assert_eq!(cfg.to_text(), Bril::from_text(bril_text).unwrap().to_string().replace(
+            "  jmp .end;\n  v: int = const 1;",
+            "  jmp .end;\n.tmp0:\n  v: int = const 1;"
+        ));
+    }
+
+    #[test]
+    fn cfg_edges() {
+        let bril_text = r#"@main(c: bool) {
+  br c .left .right;
+.left:
+  jmp .join;
+.right:
+  br c .join .join;
+.join:
+  br c .done .exit;
+.done:
+  ret;
+.exit:
+}
+"#;
+        let cfg = BrilCFG::from_text(bril_text).unwrap();
+        let main = cfg.function("main").unwrap();
+        let id = |name| main.block_id(name).unwrap();
+        let (left, right, join) = (id("left"), id("right"), id("join"));
+        assert_eq!(main.blocks[main.entry()].succs(), &[left, right]);
+        assert_eq!(main.blocks[right].succs(), &[join]);
+        assert_eq!(main.blocks[join].preds(), &[left, right]);
+        assert_eq!(main.blocks[left].preds(), &[main.entry()]);
+        assert!(main.blocks[main.entry()].preds().is_empty());
+        assert_eq!(main.exits(), vec![id("done"), id("exit")]);
+        assert_eq!(main.edges().count(), 6);
+        assert!(main.edges().all(|(from, to)| main.blocks[to].preds().contains(&from)));
+    }
 }
@@ -0,0 +1,305 @@
+// global constant propagation and folding
+use std::collections::BTreeMap;
+
+use crate::{
+    cfg::{BrilCFG, FunctionCFG},
+    dataflow::{self, Analysis, Direction, Site},
+    error::Result,
+    parser::{Instr, Literal, Opcode, Type},
+    pass::{AnalysisCache, Granularity, Pass},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Const(Literal),
+    // not a constant, or a different constant on different paths
+    Varying,
+}
+
+// known values of the variables at a point, None until a path reaches it.
+// a variable missing from the map is not defined yet on any path
+pub type Constants = Option<BTreeMap<String, Value>>;
+
+// the value of a `const`, float constants written as integers become floats
+pub(crate) fn const_value(typ: &Option<Type>, value: &Literal) -> Literal {
+    match (typ, value) {
+        (Some(Type::float), Literal::Number(n)) => Literal::Float(*n as f64),
+        _ => value.clone(),
+    }
+}
+
+// evaluate a pure op on constant operands. integer ops wrap like i64 and
+// division by zero is left to run time, float ops follow IEEE 754
+pub(crate) fn fold(op: &Opcode, args: &[Literal]) -> Option<Literal> {
+    use Literal::*;
+    use Opcode::*;
+    let value = match (op, args) {
+        (id, [value]) => value.clone(),
+        (add, [Number(a), Number(b)]) => Number(a.wrapping_add(*b)),
+        (sub, [Number(a), Number(b)]) => Number(a.wrapping_sub(*b)),
+        (mul, [Number(a), Number(b)]) => Number(a.wrapping_mul(*b)),
+        (div, [Number(_), Number(0)]) => return None,
+        (div, [Number(a), Number(b)]) => Number(a.wrapping_div(*b)),
+        (eq, [Number(a), Number(b)]) => Bool(a == b),
+        (lt, [Number(a), Number(b)]) => Bool(a < b),
+        (gt, [Number(a), Number(b)]) => Bool(a > b),
+        (le, [Number(a), Number(b)]) => Bool(a <= b),
+        (ge, [Number(a), Number(b)]) => Bool(a >= b),
+        (not, [Bool(a)]) => Bool(!a),
+        (and, [Bool(a), Bool(b)]) => Bool(*a && *b),
+        (or, [Bool(a), Bool(b)]) => Bool(*a || *b),
+        (fadd | fsub | fmul | fdiv | feq | flt | fgt | fle | fge, [a, b]) => {
+            let (a, b) = (a.as_f64()?, b.as_f64()?);
+            match op {
+                fadd => Float(a + b),
+                fsub => Float(a - b),
+                fmul => Float(a * b),
+                fdiv => Float(a / b),
+                feq => Bool(a == b),
+                flt => Bool(a < b),
+                fgt => Bool(a > b),
+                fle => Bool(a <= b),
+                _ => Bool(a >= b),
+            }
+        }
+        _ => return None,
+    };
+    Some(value)
+}
+
+pub struct ConstantPropagation;
+
+impl ConstantPropagation {
+    // the value `instr` assigns to its dest given the constants before it
+    fn evaluate(instr: &Instr, constants: &BTreeMap<String, Value>) -> Value {
+        let Instr::Instruction { op, typ, args, value, .. } = instr else {
+            return Value::Varying;
+        };
+        if *op == Opcode::cst {
+            return value.as_ref().map_or(Value::Varying, |value| Value::Const(const_value(typ, value)));
+        }
+        if !op.is_pure() {
+            return Value::Varying;
+        }
+        let mut operands = vec![];
+        for arg in args.iter().flatten() {
+            match constants.get(arg) {
+                Some(Value::Const(value)) => operands.push(value.clone()),
+                _ => return Value::Varying,
+            }
+        }
+        fold(op, &operands).map_or(Value::Varying, Value::Const)
+    }
+}
+
+impl Analysis for ConstantPropagation {
+    type Domain = Constants;
+
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self, func: &FunctionCFG) -> Constants {
+        Some(func.args.iter().flatten().map(|arg| (arg.name.clone(), Value::Varying)).collect())
+    }
+
+    fn top(&self, _func: &FunctionCFG) -> Constants {
+        None
+    }
+
+    fn meet(&self, into: &mut Constants, other: &Constants) {
+        let Some(other) = other else {
+            return;
+        };
+        let Some(into) = into.as_mut() else {
+            *into = Some(other.clone());
+            return;
+        };
+        for (var, value) in other {
+            into.entry(var.clone())
+                .and_modify(|v| {
+                    if v != value {
+                        *v = Value::Varying;
+                    }
+                })
+                .or_insert_with(|| value.clone());
+        }
+    }
+
+    fn transfer(&self, _site: Site, instr: &Instr, constants: &mut Constants) {
+        if let (Some(constants), Instr::Instruction { dest: Some(dest), .. }) = (constants, instr) {
+            let value = Self::evaluate(instr, constants);
+            constants.insert(dest.clone(), value);
+        }
+    }
+}
+
+impl Pass for ConstantPropagation {
+    fn name(&self) -> &'static str {
+        "constprop"
+    }
+
+    fn preserves_ssa(&self) -> bool {
+        true
+    }
+
+    fn granularity(&self) -> Granularity {
+        Granularity::Function
+    }
+
+    fn run_on_function(&mut self, func: &mut FunctionCFG, _cache: &mut AnalysisCache) -> Result<bool> {
+        func.const_prop()
+    }
+}
+
+impl BrilCFG {
+    pub fn const_prop(&mut self) -> Result<()> {
+        for func in self.functions.iter_mut() {
+            func.const_prop()?;
+        }
+        Ok(())
+    }
+}
+
+impl FunctionCFG {
+    // replace instructions computing a constant with a `const`, and branches
+    // on a constant with a jump. returns whether anything changed
+    pub fn const_prop(&mut self) -> Result<bool> {
+        let result = dataflow::solve(&ConstantPropagation, self);
+        let mut changed = false;
+        let mut branches_folded = false;
+        // (block, target it no longer jumps to)
+        let mut removed_edges = vec![];
+        for id in 0..self.blocks.len() {
+            let facts = result.instr_facts(&ConstantPropagation, self, id);
+            // the constants right after each instruction
+            for (instr, fact) in self.blocks[id].instrs.iter_mut().zip(facts.into_iter().skip(1)) {
+                // unreachable from the entry
+                let Some(constants) = fact else {
+                    break;
+                };
+                match instr {
+                    Instr::Instruction { op, dest: Some(dest), typ: Some(typ), .. } if *op != Opcode::cst => {
+                        if let Some(Value::Const(value)) = constants.get(dest) {
+                            *instr = Instr::new_const_instr(dest, value.clone(), typ.clone());
+                            changed = true;
+                        }
+                    }
+                    Instr::Instruction { op: Opcode::br, args: Some(args), labels: Some(labels), .. } => {
+                        if let (Some(Value::Const(Literal::Bool(cond))), [on_true, on_false]) =
+                            (args.first().and_then(|arg| constants.get(arg)), labels.as_slice())
+                        {
+                            let (target, other) = if *cond { (on_true, on_false) } else { (on_false, on_true) };
+                            if other != target {
+                                removed_edges.push((id, other.clone()));
+                            }
+                            *instr = Instr::Instruction {
+                                op: Opcode::jmp,
+                                dest: None,
+                                typ: None,
+                                args: None,
+                                funcs: None,
+                                labels: Some(vec![target.clone()]),
+                                value: None,
+                            };
+                            branches_folded = true;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for (from, target) in removed_edges {
+            if let Some(target) = self.block_id(&target) {
+                self.remove_phi_inputs(target, from);
+            }
+        }
+        if branches_folded {
+            self.resolve_cfg()?;
+        }
+        Ok(changed || branches_folded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fold_constants() {
+        use Literal::*;
+        assert_eq!(fold(&Opcode::add, &[Number(i64::MAX), Number(1)]), Some(Number(i64::MIN)));
+        assert_eq!(fold(&Opcode::mul, &[Number(1 << 62), Number(4)]), Some(Number(0)));
+        assert_eq!(fold(&Opcode::div, &[Number(i64::MIN), Number(-1)]), Some(Number(i64::MIN)));
+        assert_eq!(fold(&Opcode::div, &[Number(-7), Number(2)]), Some(Number(-3)));
+        assert_eq!(fold(&Opcode::div, &[Number(1), Number(0)]), None);
+        assert_eq!(fold(&Opcode::le, &[Number(2), Number(2)]), Some(Bool(true)));
+        assert_eq!(fold(&Opcode::or, &[Bool(false), Bool(true)]), Some(Bool(true)));
+        assert_eq!(fold(&Opcode::fdiv, &[Float(1.0), Float(0.0)]), Some(Float(f64::INFINITY)));
+        assert_eq!(fold(&Opcode::fadd, &[Number(1), Float(0.5)]), Some(Float(1.5)));
+        assert_eq!(fold(&Opcode::feq, &[Float(f64::NAN), Float(f64::NAN)]), Some(Bool(false)));
+        assert_eq!(fold(&Opcode::add, &[Number(1)]), None);
+    }
+
+    #[test]
+    fn const_prop() {
+        let bril_text = r#"@main(n: int) {
+  a: int = const 4;
+  b: int = const 2;
+  zero: int = const 0;
+  c: bool = lt b a;
+  br c .then .else;
+.then:
+  x: int = const 1;
+  jmp .join;
+.else:
+  x: int = const 2;
+.join:
+  s: int = add a b;
+  y: int = add x s;
+  q: int = div a zero;
+  m: int = mul n a;
+  print s y q m;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        let main = cfg.functions.iter_mut().find(|func| func.name == "main").unwrap();
+        assert!(main.const_prop().unwrap());
+        let bril_txt = cfg.to_text();
+        println!("bril_txt: {bril_txt}");
+        assert!(bril_txt.contains("c: bool = const true;"));
+        assert!(bril_txt.contains("jmp .then;"));
+        assert!(bril_txt.contains("s: int = const 6;"));
+        // the else branch is not taken, but constprop doesn't know which
+        // edges are executable
+        assert!(bril_txt.contains("y: int = add x s;"));
+        assert!(bril_txt.contains("q: int = div a zero;"));
+        assert!(bril_txt.contains("m: int = mul n a;"));
+
+        let main = cfg.function("main").unwrap();
+        assert_eq!(main.blocks[main.entry()].succs(), &[main.block_id("then").unwrap()]);
+    }
+
+    #[test]
+    fn const_prop_loop() {
+        let bril_text = r#"@main {
+  i: int = const 0;
+  one: int = const 1;
+  f: float = const 1;
+  h: float = const 0.5;
+.loop:
+  g: float = fadd f h;
+  two: int = add one one;
+  i: int = add i two;
+  c: bool = lt i two;
+  br c .loop .done;
+.done:
+  print i g;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        cfg.const_prop().unwrap();
+        let bril_txt = cfg.to_text();
+        println!("bril_txt: {bril_txt}");
+        assert!(bril_txt.contains("g: float = const 1.5;"));
+        assert!(bril_txt.contains("two: int = const 2;"));
+        assert!(bril_txt.contains("i: int = add i two;"));
+        assert!(bril_txt.contains("br c .loop .done;"));
+    }
+}
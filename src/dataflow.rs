@@ -0,0 +1,272 @@
+// generic worklist solver for dataflow analyses over a function's cfg
+use std::collections::BTreeSet;
+
+use crate::{
+    cfg::{BlockId, FunctionCFG},
+    parser::Instr,
+};
+
+// position of an instruction, `index` into the instrs of `block`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Site {
+    pub block: BlockId,
+    pub index: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+// an analysis is a lattice (`top` and `meet`) plus a transfer function.
+// forward analyses flow facts from the entry along edges, backward ones
+// from the exits against them
+pub trait Analysis {
+    type Domain: Clone + PartialEq;
+
+    const DIRECTION: Direction;
+
+    // the fact at the function entry (forward) or at its exits (backward)
+    fn boundary(&self, func: &FunctionCFG) -> Self::Domain;
+
+    // the starting fact for all other blocks, `meet(top, x) == x`
+    fn top(&self, func: &FunctionCFG) -> Self::Domain;
+
+    // combine the facts of two incoming edges into `into`
+    fn meet(&self, into: &mut Self::Domain, other: &Self::Domain);
+
+    // move `fact` across `instr`: from before to after it for forward
+    // analyses, from after to before it for backward ones
+    fn transfer(&self, site: Site, instr: &Instr, fact: &mut Self::Domain);
+
+    fn transfer_block(&self, func: &FunctionCFG, block: BlockId, fact: &mut Self::Domain) {
+        let instrs = func.blocks[block].instrs().iter().enumerate();
+        let mut transfer = |(index, instr)| self.transfer(Site { block, index }, instr, fact);
+        match Self::DIRECTION {
+            Direction::Forward => instrs.for_each(&mut transfer),
+            Direction::Backward => instrs.rev().for_each(&mut transfer),
+        }
+    }
+}
+
+// facts at the start (`ins`) and end (`outs`) of every block, in program
+// order regardless of the direction of the analysis
+pub struct DataflowResult<D> {
+    pub ins: Vec<D>,
+    pub outs: Vec<D>,
+}
+
+impl<D: Clone> DataflowResult<D> {
+    pub fn block_in(&self, block: BlockId) -> &D {
+        &self.ins[block]
+    }
+
+    pub fn block_out(&self, block: BlockId) -> &D {
+        &self.outs[block]
+    }
+
+    // facts at every program point of `block`: `facts[i]` holds right before
+    // instruction `i`, the last entry at the end of the block
+    pub fn instr_facts<A>(&self, analysis: &A, func: &FunctionCFG, block: BlockId) -> Vec<D>
+    where
+        A: Analysis<Domain = D>,
+    {
+        let instrs = func.blocks[block].instrs();
+        match A::DIRECTION {
+            Direction::Forward => {
+                let mut fact = self.ins[block].clone();
+                let mut facts = vec![fact.clone()];
+                for (index, instr) in instrs.iter().enumerate() {
+                    analysis.transfer(Site { block, index }, instr, &mut fact);
+                    facts.push(fact.clone());
+                }
+                facts
+            }
+            Direction::Backward => {
+                let mut fact = self.outs[block].clone();
+                let mut facts = vec![fact.clone()];
+                for (index, instr) in instrs.iter().enumerate().rev() {
+                    analysis.transfer(Site { block, index }, instr, &mut fact);
+                    facts.push(fact.clone());
+                }
+                facts.reverse();
+                facts
+            }
+        }
+    }
+}
+
+pub fn solve<A: Analysis>(analysis: &A, func: &FunctionCFG) -> DataflowResult<A::Domain> {
+    let n = func.blocks.len();
+    // reverse postorder visits most predecessors first, its reverse most
+    // successors first. unreachable blocks are solved too, after the rest
+    let mut order = func.reverse_postorder();
+    let mut seen = vec![false; n];
+    for id in &order {
+        seen[*id] = true;
+    }
+    order.extend((0..n).filter(|id| !seen[*id]));
+    if A::DIRECTION == Direction::Backward {
+        order.reverse();
+    }
+    let mut position = vec![0; n];
+    for (i, id) in order.iter().enumerate() {
+        position[*id] = i;
+    }
+
+    let top = analysis.top(func);
+    let boundary = analysis.boundary(func);
+    // `before` is where facts flow in, `after` where they flow out
+    let mut before = vec![top.clone(); n];
+    let mut after = vec![top.clone(); n];
+    let mut worklist = (0..n).collect::<BTreeSet<_>>();
+
+    while let Some(pos) = worklist.pop_first() {
+        let id = order[pos];
+        let block = &func.blocks[id];
+        let (incoming, outgoing) = match A::DIRECTION {
+            Direction::Forward => (block.preds(), block.succs()),
+            Direction::Backward => (block.succs(), block.preds()),
+        };
+        let is_boundary = match A::DIRECTION {
+            Direction::Forward => id == func.entry(),
+            Direction::Backward => block.succs().is_empty(),
+        };
+
+        let mut fact = if is_boundary { boundary.clone() } else { top.clone() };
+        for other in incoming {
+            analysis.meet(&mut fact, &after[*other]);
+        }
+        before[id] = fact.clone();
+        analysis.transfer_block(func, id, &mut fact);
+        if fact != after[id] {
+            after[id] = fact;
+            worklist.extend(outgoing.iter().map(|other| position[*other]));
+        }
+    }
+
+    match A::DIRECTION {
+        Direction::Forward => DataflowResult { ins: before, outs: after },
+        Direction::Backward => DataflowResult { ins: after, outs: before },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::cfg::BrilCFG;
+
+    // variables assigned on every path to a point, a forward must analysis
+    struct DefinitelyAssigned;
+
+    impl Analysis for DefinitelyAssigned {
+        // None is top, "no path seen yet"
+        type Domain = Option<BTreeSet<String>>;
+
+        const DIRECTION: Direction = Direction::Forward;
+
+        fn boundary(&self, func: &FunctionCFG) -> Self::Domain {
+            Some(func.args.iter().flatten().map(|arg| arg.name.clone()).collect())
+        }
+
+        fn top(&self, _func: &FunctionCFG) -> Self::Domain {
+            None
+        }
+
+        fn meet(&self, into: &mut Self::Domain, other: &Self::Domain) {
+            match (into.as_mut(), other) {
+                (_, None) => {}
+                (None, Some(other)) => *into = Some(other.clone()),
+                (Some(into), Some(other)) => into.retain(|var| other.contains(var)),
+            }
+        }
+
+        fn transfer(&self, _site: Site, instr: &Instr, fact: &mut Self::Domain) {
+            if let (Some(fact), Instr::Instruction { dest: Some(dest), .. }) = (fact, instr) {
+                fact.insert(dest.clone());
+            }
+        }
+    }
+
+    // variables that may be read later, the classic backward may analysis
+    struct Used;
+
+    impl Analysis for Used {
+        type Domain = BTreeSet<String>;
+
+        const DIRECTION: Direction = Direction::Backward;
+
+        fn boundary(&self, _func: &FunctionCFG) -> Self::Domain {
+            BTreeSet::new()
+        }
+
+        fn top(&self, _func: &FunctionCFG) -> Self::Domain {
+            BTreeSet::new()
+        }
+
+        fn meet(&self, into: &mut Self::Domain, other: &Self::Domain) {
+            into.extend(other.iter().cloned());
+        }
+
+        fn transfer(&self, _site: Site, instr: &Instr, fact: &mut Self::Domain) {
+            if let Instr::Instruction { args: Some(args), .. } = instr {
+                fact.extend(args.iter().cloned());
+            }
+        }
+    }
+
+    fn set(vars: &[&str]) -> BTreeSet<String> {
+        vars.iter().map(|v| v.to_string()).collect()
+    }
+
+    const PROGRAM: &str = r#"@main(n: int) {
+  i: int = const 0;
+.loop:
+  c: bool = lt i n;
+  br c .body .done;
+.body:
+  x: int = add i n;
+  i: int = id x;
+  jmp .loop;
+.done:
+  print i;
+}"#;
+
+    #[test]
+    fn forward_analysis() {
+        let cfg = BrilCFG::from_text(PROGRAM).unwrap();
+        let main = cfg.function("main").unwrap();
+        let id = |name| main.block_id(name).unwrap();
+        let result = solve(&DefinitelyAssigned, main);
+        assert_eq!(result.block_in(id("loop")), &Some(set(&["n", "i"])));
+        assert_eq!(result.block_out(id("loop")), &Some(set(&["n", "i", "c"])));
+        assert_eq!(result.block_out(id("body")), &Some(set(&["n", "i", "c", "x"])));
+        // x is only assigned on the path through the body
+        assert_eq!(result.block_in(id("done")), &Some(set(&["n", "i", "c"])));
+
+        let facts = result.instr_facts(&DefinitelyAssigned, main, id("body"));
+        assert_eq!(facts.len(), 4);
+        assert_eq!(facts[1], Some(set(&["n", "i", "c", "x"])));
+    }
+
+    #[test]
+    fn backward_analysis() {
+        let cfg = BrilCFG::from_text(PROGRAM).unwrap();
+        let main = cfg.function("main").unwrap();
+        let id = |name| main.block_id(name).unwrap();
+        let result = solve(&Used, main);
+        assert_eq!(result.block_out(id("done")), &set(&[]));
+        assert_eq!(result.block_in(id("done")), &set(&["i"]));
+        assert_eq!(result.block_in(id("loop")), &set(&["c", "i", "n", "x"]));
+        assert_eq!(result.block_out(main.entry()), result.block_in(id("loop")));
+
+        let facts = result.instr_facts(&Used, main, id("loop"));
+        assert_eq!(facts.len(), 3);
+        assert_eq!(facts[1], set(&["c", "i", "n", "x"]));
+        assert_eq!(&facts[0], result.block_in(id("loop")));
+        assert_eq!(&facts[2], result.block_out(id("loop")));
+    }
+}
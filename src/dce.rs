@@ -1,117 +1,240 @@
 use std::collections::{HashMap, HashSet};
 
-use crate::cfg::{Block, BrilCFG};
+use crate::{
+    cfg::{Block, BrilCFG, FunctionCFG},
+    error::{Error, Result},
+    liveness::Liveness,
+    pass::{AnalysisCache, Granularity, Pass},
+};
+
+// deletes pure definitions that are never read anywhere in the function, and
+// definitions overwritten later in their block before being read. a div is
+// only deleted when its divisor is a known nonzero constant
+pub struct TrivialDce;
+
+impl Pass for TrivialDce {
+    fn name(&self) -> &'static str {
+        "tdce"
+    }
+
+    fn preserves_ssa(&self) -> bool {
+        true
+    }
+
+    fn granularity(&self) -> Granularity {
+        Granularity::Function
+    }
+
+    fn run_on_function(&mut self, func: &mut FunctionCFG, _cache: &mut AnalysisCache) -> Result<bool> {
+        func.trivial_dce()
+    }
+}
+
+// deletes pure definitions that are not live afterwards, divs as in tdce
+pub struct Dce;
+
+impl Pass for Dce {
+    fn name(&self) -> &'static str {
+        "dce"
+    }
+
+    fn preserves_ssa(&self) -> bool {
+        true
+    }
+
+    fn granularity(&self) -> Granularity {
+        Granularity::Function
+    }
+
+    fn run_on_function(&mut self, func: &mut FunctionCFG, _cache: &mut AnalysisCache) -> Result<bool> {
+        func.dce()
+    }
+}
 
 impl BrilCFG {
-    pub fn trivial_dce(&mut self) {
-        for block in self.blocks.iter_mut() {
-            block.trivial_dce();
-            block.trivial_dce2();
+    pub fn trivial_dce(&mut self) -> Result<()> {
+        for func in self.functions.iter_mut() {
+            func.trivial_dce()?;
         }
+        Ok(())
+    }
+
+    pub fn dce(&mut self) -> Result<()> {
+        for func in self.functions.iter_mut() {
+            func.dce()?;
+        }
+        Ok(())
     }
 }
 
-use crate::parser::Instr::{self, *};
-impl Block {
-    pub fn iterate_every_instr<F>(&self, mut f: F)
-    where
-        F: FnMut(&Instr) -> (),
-    {
-        for instr in &self.instrs {
-            match instr {
-                instr @ Instruction { .. } => {
-                    f(instr);
-                }
-                Label { label } => {
-                    panic!("Unexpected label: {label}");
-                }
+impl FunctionCFG {
+    // returns whether anything was deleted
+    pub fn trivial_dce(&mut self) -> Result<bool> {
+        let nonzero = self.nonzero_consts();
+        let mut changed = false;
+        loop {
+            // a use in any block keeps the definition, we don't know which
+            // definitions reach it
+            let mut used = HashSet::new();
+            for block in &self.blocks {
+                block.iterate_every_instr(|instr| {
+                    if let Instruction { args: Some(args), .. } = instr {
+                        used.extend(args.iter().cloned());
+                    }
+                })?;
+            }
+            let mut flag = false;
+            for block in self.blocks.iter_mut() {
+                flag |= block.remove_unused(&used, &nonzero);
+            }
+            if !flag {
+                break;
             }
+            changed = true;
+        }
+        for block in self.blocks.iter_mut() {
+            let before = block.instrs.len();
+            block.trivial_dce2(&nonzero)?;
+            changed |= block.instrs.len() != before;
         }
+        Ok(changed)
     }
 
-    pub fn trivial_dce(&mut self) {
+    // liveness based, so a definition read only by a later block survives
+    // while one that is overwritten on every path is deleted
+    pub fn dce(&mut self) -> Result<bool> {
+        let nonzero = self.nonzero_consts();
+        let mut changed = false;
         loop {
+            let live = Liveness::compute(self);
             let mut flag = false;
-            let mut to_be_deleted = vec![];
-            let mut used = HashSet::new();
-
-            self.iterate_every_instr(|instr| {
-                if let Instruction { args, .. } = instr {
-                    if let Some(args) = args {
-                        for arg in args {
-                            used.insert(arg.clone());
-                        }
-                    }
-                }
-            });
-            self.iterate_every_instr(|instr| {
-                if let Instruction { dest, .. } = instr {
+            for (id, block) in self.blocks.iter_mut().enumerate() {
+                let mut live = live.block_out(id).clone();
+                let mut keep = vec![true; block.instrs.len()];
+                for (i, instr) in block.instrs.iter().enumerate().rev() {
+                    let Instruction { dest, args, .. } = instr else {
+                        return Err(Error::MalformedCfg(format!("unexpected label in block {}", block.name)));
+                    };
                     if let Some(dest) = dest {
-                        if !used.contains(dest) {
-                            to_be_deleted.push(instr.clone());
+                        if removable(instr, &nonzero) && !live.contains(dest) {
+                            keep[i] = false;
                             flag = true;
+                            continue;
                         }
+                        live.remove(dest);
                     }
+                    live.extend(args.iter().flatten().cloned());
                 }
-            });
+                let mut keep = keep.into_iter();
+                block.instrs.retain(|_| keep.next().unwrap());
+            }
+            // deleting a use can make more definitions dead
             if !flag {
-                break;
+                return Ok(changed);
+            }
+            changed = true;
+        }
+    }
+
+    // variables holding a nonzero int constant wherever they are read: not
+    // arguments, and every definition is such a constant
+    fn nonzero_consts(&self) -> HashSet<String> {
+        let mut nonzero = HashSet::new();
+        let mut other = self.args.iter().flatten().map(|arg| arg.name.clone()).collect::<HashSet<_>>();
+        for block in &self.blocks {
+            for instr in &block.instrs {
+                match instr {
+                    Instruction { op: Opcode::cst, dest: Some(dest), value: Some(Literal::Number(n)), .. } if *n != 0 => {
+                        nonzero.insert(dest.clone());
+                    }
+                    Instruction { dest: Some(dest), .. } => {
+                        other.insert(dest.clone());
+                    }
+                    _ => {}
+                }
             }
+        }
+        nonzero.retain(|var| !other.contains(var));
+        nonzero
+    }
+}
 
-            let new_instr = self
-                .instrs
-                .iter()
-                .filter(|x| !to_be_deleted.contains(x))
-                .map(|x| x.clone())
-                .collect::<Vec<_>>();
-            self.instrs = new_instr;
+use crate::parser::{Instr::{self, *}, Literal, Opcode};
+
+// pure and can't trap, so deleting it when unused doesn't change anything
+fn removable(instr: &Instr, nonzero: &HashSet<String>) -> bool {
+    match instr {
+        Instruction { op, args, .. } if op.can_trap() => {
+            matches!(args.as_deref(), Some([_, divisor]) if nonzero.contains(divisor))
         }
+        Instruction { op, .. } => op.is_pure(),
+        _ => false,
     }
+}
 
-    pub fn trivial_dce2(&mut self) {
+impl Block {
+    pub fn iterate_every_instr<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&Instr),
+    {
+        for instr in &self.instrs {
+            match instr {
+                instr @ Instruction { .. } => {
+                    f(instr);
+                }
+                Label { label } => {
+                    return Err(Error::MalformedCfg(format!("unexpected label: {label}")));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // delete pure definitions whose dest is not in `used`, `nonzero` holds
+    // the variables known to be nonzero constants
+    pub fn remove_unused(&mut self, used: &HashSet<String>, nonzero: &HashSet<String>) -> bool {
+        let before = self.instrs.len();
+        // side effects (call, load, ...) must stay even if the dest is unused
+        self.instrs
+            .retain(|instr| !matches!(instr, Instruction { dest: Some(dest), .. } if removable(instr, nonzero) && !used.contains(dest)));
+        self.instrs.len() != before
+    }
+
+    pub fn trivial_dce2(&mut self, nonzero: &HashSet<String>) -> Result<()> {
         loop {
-            let mut flag = false;
-            let mut to_be_deleted = vec![];
-            let mut last_defs: HashMap<String, Instr> = HashMap::new();
+            // positions in the block, an identical instruction elsewhere may
+            // still be needed
+            let mut to_be_deleted = HashSet::new();
+            let mut last_defs: HashMap<String, usize> = HashMap::new();
+            let mut index = 0;
             self.iterate_every_instr(|instr| {
-                if let Instruction {args, dest, ..}  =instr {
+                if let Instruction { args, dest, .. } = instr {
                     // for each use
-                    if let Some(args) = args {
-                        for arg in args {
-                            if last_defs.contains_key(arg) {
-                                // def used
-                                last_defs.remove(arg);
-                            }
-                        }
+                    for arg in args.iter().flatten() {
+                        // def used
+                        last_defs.remove(arg);
                     }
                     // for each defines
                     if let Some(dest) = dest {
-                        if last_defs.contains_key(dest) {
-                            // FIXME: what if the current instr is exact same as last_def instr
-                            //          in this case, current instr will be deleted as well
-                            //          i.e.  a: int = const 1;
-                            //                a: int = const 1;
-                            to_be_deleted.push(last_defs.get(dest).unwrap().clone());
-                            flag = true;
-                        } else {
-                            last_defs.insert(dest.clone(), instr.clone());
+                        if let Some(last_def) = last_defs.insert(dest.clone(), index) {
+                            if removable(&self.instrs[last_def], nonzero) {
+                                to_be_deleted.insert(last_def);
+                            }
                         }
                     }
                 }
-            });
-
+                index += 1;
+            })?;
 
-            if !flag {
-                break;
+            if to_be_deleted.is_empty() {
+                return Ok(());
             }
 
-            let new_instr = self
-                .instrs
-                .iter()
-                .filter(|x| !to_be_deleted.contains(x))
-                .map(|x| x.clone())
-                .collect::<Vec<_>>();
-            self.instrs = new_instr;
+            let mut index = 0;
+            self.instrs.retain(|_| {
+                index += 1;
+                !to_be_deleted.contains(&(index - 1))
+            });
         }
     }
 }
@@ -119,6 +242,7 @@ impl Block {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::pass::PassManager;
 
     #[test]
     fn trivial_dce() {
@@ -131,14 +255,135 @@ mod tests {
         print d;
 }"#;
 
-        let mut cfg = BrilCFG::from_text(bril_text);
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
 
 
-        cfg.trivial_dce();
+        cfg.trivial_dce().unwrap();
         let bril_txt = cfg.to_text();
 
         println!("bril_txt: {bril_txt}");
         assert!(!bril_txt.contains("c: int = const 1;"));
         assert!(!bril_txt.contains("a: int = const 4;"));
     }
+
+    #[test]
+    fn trivial_dce_identical_defs() {
+        let bril_text = r#"@main {
+  a: int = const 1;
+  a: int = const 1;
+  print a;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        PassManager::from_pipeline("tdce").unwrap().run(&mut cfg).unwrap();
+        let bril_txt = cfg.to_text();
+        println!("bril_txt: {bril_txt}");
+        // only the overwritten one goes
+        assert_eq!(bril_txt.matches("a: int = const 1;").count(), 1);
+        assert_eq!(cfg.interpret(&[]).unwrap(), "1\n");
+    }
+
+    #[test]
+    fn keep_side_effects() {
+        let bril_text = r#"@main{
+        one: int = const 1;
+        p: ptr<int> = alloc one;
+        store p one;
+        v: int = load p;
+        free p;
+}"#;
+
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        cfg.trivial_dce().unwrap();
+        let bril_txt = cfg.to_text();
+
+        println!("bril_txt: {bril_txt}");
+        assert!(bril_txt.contains("store p one;"));
+        assert!(bril_txt.contains("free p;"));
+        assert!(bril_txt.contains("v: int = load p;"));
+    }
+
+    #[test]
+    fn keep_trapping_div() {
+        let bril_text = r#"@main(x: int, n: int) {
+        zero: int = const 0;
+        two: int = const 2;
+        a: int = div x zero;
+        b: int = div x two;
+        c: int = div x n;
+        b: int = div x zero;
+        print x;
+}"#;
+
+        for f in [BrilCFG::trivial_dce, BrilCFG::dce] {
+            let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+            f(&mut cfg).unwrap();
+            let bril_txt = cfg.to_text();
+
+            println!("bril_txt: {bril_txt}");
+            assert!(bril_txt.contains("a: int = div x zero;"));
+            assert!(!bril_txt.contains("div x two"));
+            assert!(bril_txt.contains("c: int = div x n;"));
+            assert!(bril_txt.contains("b: int = div x zero;"));
+            assert!(!bril_txt.contains("two: int"));
+        }
+    }
+
+    #[test]
+    fn uses_in_other_blocks() {
+        let bril_text = r#"@main(c: bool) {
+  x: int = const 1;
+  y: int = const 2;
+  br c .then .else;
+.then:
+  print x;
+  ret;
+.else:
+  y: int = const 3;
+  print y;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        cfg.trivial_dce().unwrap();
+        assert!(cfg.to_text().contains("x: int = const 1;"));
+
+        cfg.dce().unwrap();
+        let bril_txt = cfg.to_text();
+        println!("bril_txt: {bril_txt}");
+        assert!(bril_txt.contains("x: int = const 1;"));
+        // overwritten on the only path that reads it
+        assert!(!bril_txt.contains("y: int = const 2;"));
+        assert!(bril_txt.contains("y: int = const 3;"));
+    }
+
+    #[test]
+    fn global_dce() {
+        let bril_text = r#"@main(n: int) {
+  one: int = const 1;
+  i: int = const 0;
+  unused: int = add n one;
+  r: int = call @f n;
+.loop:
+  dead: int = mul i i;
+  i: int = add i one;
+  c: bool = lt i n;
+  br c .loop .done;
+.done:
+  print i;
+}
+
+@f(n: int): int {
+  ret n;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        let mut dce = Dce;
+        let main = cfg.functions.iter_mut().find(|func| func.name == "main").unwrap();
+        assert!(dce.run_on_function(main, &mut AnalysisCache::default()).unwrap());
+        assert!(!dce.run_on_function(main, &mut AnalysisCache::default()).unwrap());
+        let bril_txt = cfg.to_text();
+        println!("bril_txt: {bril_txt}");
+        assert!(!bril_txt.contains("unused"));
+        assert!(!bril_txt.contains("dead"));
+        assert!(bril_txt.contains("r: int = call @f n;"));
+        assert!(bril_txt.contains("i: int = add i one;"));
+        assert!(bril_txt.contains("one: int = const 1;"));
+    }
 }
@@ -0,0 +1,580 @@
+// dominance over a function's cfg, using the iterative algorithm from
+// Cooper, Harvey and Kennedy, "A Simple, Fast Dominance Algorithm"
+use std::collections::BTreeSet;
+
+use crate::cfg::{BlockId, FunctionCFG};
+
+pub struct Dominators {
+    entry: BlockId,
+    // None for the entry and for blocks unreachable from it
+    idom: Vec<Option<BlockId>>,
+    children: Vec<Vec<BlockId>>,
+    // pre and post order numbers of the dominator tree, `a` dominates `b`
+    // iff b's interval is nested in a's
+    pre: Vec<usize>,
+    post: Vec<usize>,
+    reachable: Vec<bool>,
+}
+
+// immediate dominators of a graph given in reverse postorder of the nodes
+// reachable from `rpo[0]`
+pub(crate) fn compute_idoms<P>(num_nodes: usize, rpo: &[usize], preds: P) -> Vec<Option<usize>>
+where
+    P: Fn(usize) -> Vec<usize>,
+{
+    const UNDEF: usize = usize::MAX;
+    let mut order = vec![UNDEF; num_nodes];
+    for (i, node) in rpo.iter().enumerate() {
+        order[*node] = i;
+    }
+    let mut idom = vec![UNDEF; num_nodes];
+    let Some(&entry) = rpo.first() else {
+        return vec![None; num_nodes];
+    };
+    idom[entry] = entry;
+
+    let intersect = |idom: &[usize], mut a: usize, mut b: usize| {
+        while a != b {
+            while order[a] > order[b] {
+                a = idom[a];
+            }
+            while order[b] > order[a] {
+                b = idom[b];
+            }
+        }
+        a
+    };
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &node in rpo.iter().skip(1) {
+            let mut new_idom = UNDEF;
+            for pred in preds(node) {
+                // skip unreachable predecessors and ones not processed yet
+                if order[pred] == UNDEF || idom[pred] == UNDEF {
+                    continue;
+                }
+                new_idom = if new_idom == UNDEF {
+                    pred
+                } else {
+                    intersect(&idom, pred, new_idom)
+                };
+            }
+            if new_idom != UNDEF && idom[node] != new_idom {
+                idom[node] = new_idom;
+                changed = true;
+            }
+        }
+    }
+
+    idom.iter()
+        .enumerate()
+        .map(|(node, d)| (node != entry && *d != UNDEF).then_some(*d))
+        .collect()
+}
+
+// depth first postorder of the nodes reachable from `start`
+fn postorder<S>(num_nodes: usize, start: usize, succs: S) -> Vec<usize>
+where
+    S: Fn(usize) -> Vec<usize>,
+{
+    let mut visited = vec![false; num_nodes];
+    let mut order = vec![];
+    let mut stack = vec![(start, succs(start), 0)];
+    visited[start] = true;
+    while let Some((node, node_succs, next)) = stack.last_mut() {
+        if let Some(&succ) = node_succs.get(*next) {
+            *next += 1;
+            if !visited[succ] {
+                visited[succ] = true;
+                stack.push((succ, succs(succ), 0));
+            }
+        } else {
+            order.push(*node);
+            stack.pop();
+        }
+    }
+    order
+}
+
+// frontier of every node: the nodes where its dominance ends, see
+// Cytron et al. as simplified by Cooper, Harvey and Kennedy
+fn frontiers<P>(num_nodes: usize, dom: &Dominators, preds: P) -> Vec<Vec<usize>>
+where
+    P: Fn(usize) -> Vec<usize>,
+{
+    let mut df = vec![BTreeSet::new(); num_nodes];
+    for node in (0..num_nodes).filter(|n| dom.is_reachable(*n)) {
+        for pred in preds(node).into_iter().filter(|p| dom.is_reachable(*p)) {
+            let mut runner = Some(pred);
+            while let Some(r) = runner {
+                if dom.strictly_dominates(r, node) {
+                    break;
+                }
+                df[r].insert(node);
+                runner = dom.idom(r);
+            }
+        }
+    }
+    df.into_iter().map(|set| set.into_iter().collect()).collect()
+}
+
+impl Dominators {
+    pub fn compute(func: &FunctionCFG) -> Self {
+        let rpo = func.reverse_postorder();
+        let idom = compute_idoms(func.blocks.len(), &rpo, |id| func.blocks[id].preds().to_vec());
+        Self::from_idoms(func.entry(), idom, &rpo)
+    }
+
+    pub(crate) fn from_idoms(entry: BlockId, idom: Vec<Option<BlockId>>, reachable_nodes: &[BlockId]) -> Self {
+        let n = idom.len();
+        let mut children = vec![vec![]; n];
+        for &node in reachable_nodes {
+            if let Some(parent) = idom[node] {
+                children[parent].push(node);
+            }
+        }
+        let mut reachable = vec![false; n];
+        for &node in reachable_nodes {
+            reachable[node] = true;
+        }
+
+        // number the dominator tree
+        let mut pre = vec![0; n];
+        let mut post = vec![0; n];
+        let mut counter = 0;
+        let mut stack = vec![(entry, 0)];
+        pre[entry] = counter;
+        counter += 1;
+        while let Some((node, next)) = stack.last_mut() {
+            let node = *node;
+            if let Some(&child) = children[node].get(*next) {
+                *next += 1;
+                pre[child] = counter;
+                counter += 1;
+                stack.push((child, 0));
+            } else {
+                post[node] = counter;
+                counter += 1;
+                stack.pop();
+            }
+        }
+
+        Self {
+            entry,
+            idom,
+            children,
+            pre,
+            post,
+            reachable,
+        }
+    }
+
+    pub fn entry(&self) -> BlockId {
+        self.entry
+    }
+
+    pub fn idom(&self, block: BlockId) -> Option<BlockId> {
+        self.idom[block]
+    }
+
+    // children of `block` in the dominator tree
+    pub fn children(&self, block: BlockId) -> &[BlockId] {
+        &self.children[block]
+    }
+
+    pub fn is_reachable(&self, block: BlockId) -> bool {
+        self.reachable[block]
+    }
+
+    // every block dominates itself, unreachable blocks dominate and are
+    // dominated by nothing
+    pub fn dominates(&self, a: BlockId, b: BlockId) -> bool {
+        self.reachable[a] && self.reachable[b] && self.pre[a] <= self.pre[b] && self.post[b] <= self.post[a]
+    }
+
+    pub fn strictly_dominates(&self, a: BlockId, b: BlockId) -> bool {
+        a != b && self.dominates(a, b)
+    }
+
+    // the dominator set of `block`, from the block itself up to the entry
+    pub fn dominators(&self, block: BlockId) -> Vec<BlockId> {
+        if !self.reachable[block] {
+            return vec![];
+        }
+        let mut doms = vec![block];
+        let mut cur = block;
+        while let Some(parent) = self.idom[cur] {
+            doms.push(parent);
+            cur = parent;
+        }
+        doms
+    }
+
+    // dominator tree in preorder, parents before children
+    pub fn preorder(&self) -> Vec<BlockId> {
+        let mut order = vec![];
+        let mut stack = vec![self.entry];
+        while let Some(node) = stack.pop() {
+            order.push(node);
+            stack.extend(self.children[node].iter().rev());
+        }
+        order
+    }
+
+    // for every block, the blocks where its dominance ends. this is where
+    // ssa construction places phis
+    pub fn frontiers(&self, func: &FunctionCFG) -> Vec<Vec<BlockId>> {
+        frontiers(func.blocks.len(), self, |id| func.blocks[id].preds().to_vec())
+    }
+}
+
+// dominance on the reversed cfg. all exits are joined by a virtual exit
+// node with id `blocks.len()`, which is the root of the post-dominator tree.
+// blocks stuck in an infinite loop can never reach an exit, so one block of
+// each such loop is connected to the virtual exit as well
+pub struct PostDominators {
+    tree: Dominators,
+    // blocks with an edge to the virtual exit, real and pseudo exits
+    exits: Vec<BlockId>,
+}
+
+impl PostDominators {
+    pub fn compute(func: &FunctionCFG) -> Self {
+        let n = func.blocks.len();
+        let virtual_exit = n;
+        let reachable = func.reverse_postorder();
+        let mut exits = func.exits();
+        exits.retain(|id| reachable.contains(id));
+
+        let reverse_succs = |exits: &[BlockId], node: usize| {
+            if node == virtual_exit {
+                exits.to_vec()
+            } else {
+                func.blocks[node].preds().to_vec()
+            }
+        };
+        let mut reaches_exit = postorder(n + 1, virtual_exit, |node| reverse_succs(&exits, node));
+        // pick the latest block of an infinite loop in forward order, the
+        // bottom of the loop, until every reachable block reaches an exit
+        while let Some(&stuck) = reachable.iter().rev().find(|id| !reaches_exit.contains(id)) {
+            exits.push(stuck);
+            reaches_exit = postorder(n + 1, virtual_exit, |node| reverse_succs(&exits, node));
+        }
+
+        let rpo = reaches_exit.into_iter().rev().collect::<Vec<_>>();
+        let reverse_preds = |node: usize| {
+            if node == virtual_exit {
+                return vec![];
+            }
+            let mut preds = func.blocks[node].succs().to_vec();
+            if exits.contains(&node) {
+                preds.push(virtual_exit);
+            }
+            preds
+        };
+        let idom = compute_idoms(n + 1, &rpo, reverse_preds);
+        Self {
+            tree: Dominators::from_idoms(virtual_exit, idom, &rpo),
+            exits,
+        }
+    }
+
+    pub fn virtual_exit(&self) -> BlockId {
+        self.tree.entry()
+    }
+
+    // blocks connected to the virtual exit, the `ret` blocks and blocks
+    // falling off the end plus one block of every infinite loop
+    pub fn exits(&self) -> &[BlockId] {
+        &self.exits
+    }
+
+    // the post-dominator tree, rooted at the virtual exit
+    pub fn tree(&self) -> &Dominators {
+        &self.tree
+    }
+
+    // None if `block` is only post-dominated by the virtual exit
+    pub fn ipdom(&self, block: BlockId) -> Option<BlockId> {
+        self.tree.idom(block).filter(|id| *id != self.virtual_exit())
+    }
+
+    pub fn post_dominates(&self, a: BlockId, b: BlockId) -> bool {
+        self.tree.dominates(a, b)
+    }
+
+    pub fn strictly_post_dominates(&self, a: BlockId, b: BlockId) -> bool {
+        self.tree.strictly_dominates(a, b)
+    }
+
+    // frontiers on the reversed cfg: `b` is in the post-dominance frontier of
+    // `a` when `a` post-dominates a successor of `b` but not `b` itself, i.e.
+    // `a` is control dependent on `b`
+    pub fn frontiers(&self, func: &FunctionCFG) -> Vec<Vec<BlockId>> {
+        let n = func.blocks.len();
+        let reverse_preds = |node: usize| {
+            if node == n {
+                return vec![];
+            }
+            let mut preds = func.blocks[node].succs().to_vec();
+            if self.exits.contains(&node) {
+                preds.push(n);
+            }
+            preds
+        };
+        let mut df = frontiers(n + 1, &self.tree, reverse_preds);
+        df.truncate(n);
+        df
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::cfg::BrilCFG;
+
+    // xorshift, good enough to shuffle cfgs around
+    pub(crate) struct Rng(u64);
+
+    impl Rng {
+        pub(crate) fn new(seed: u64) -> Self {
+            Self(seed.max(1))
+        }
+
+        pub(crate) fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    // a function with `n` labelled blocks jumping around at random
+    pub(crate) fn random_function(rng: &mut Rng, n: usize) -> String {
+        let mut text = String::from("@main(c: bool) {\n");
+        for i in 0..n {
+            text.push_str(&format!(".b{i}:\n  print c;\n"));
+            match rng.below(5) {
+                0 => text.push_str("  ret;\n"),
+                1 => text.push_str(&format!("  jmp .b{};\n", rng.below(n))),
+                // fall through
+                2 if i + 1 < n => {}
+                _ => text.push_str(&format!("  br c .b{} .b{};\n", rng.below(n), rng.below(n))),
+            }
+        }
+        text.push_str("}\n");
+        text
+    }
+
+    // textbook fixpoint: dom(entry) = {entry}, dom(b) = {b} + meet of dom(preds)
+    fn naive_dominators(func: &FunctionCFG) -> Vec<BTreeSet<BlockId>> {
+        let n = func.blocks.len();
+        let rpo = func.reverse_postorder();
+        let all = rpo.iter().copied().collect::<BTreeSet<_>>();
+        let mut doms = vec![all.clone(); n];
+        doms[func.entry()] = BTreeSet::from([func.entry()]);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in rpo.iter().skip(1) {
+                let mut new = all.clone();
+                for p in func.blocks[b].preds().iter().filter(|p| all.contains(p)) {
+                    new = new.intersection(&doms[*p]).copied().collect();
+                }
+                new.insert(b);
+                if new != doms[b] {
+                    doms[b] = new;
+                    changed = true;
+                }
+            }
+        }
+        for (b, dom) in doms.iter_mut().enumerate() {
+            if !all.contains(&b) {
+                dom.clear();
+            }
+        }
+        doms
+    }
+
+    #[test]
+    fn dominator_tree() {
+        let bril_text = r#"@main(c: bool) {
+.entry:
+  br c .left .right;
+.left:
+  jmp .join;
+.right:
+  br c .join .loop;
+.loop:
+  br c .loop .join;
+.join:
+  ret;
+.dead:
+  jmp .join;
+}"#;
+        let cfg = BrilCFG::from_text(bril_text).unwrap();
+        let main = cfg.function("main").unwrap();
+        let id = |name| main.block_id(name).unwrap();
+        let dom = Dominators::compute(main);
+        assert_eq!(dom.idom(main.entry()), None);
+        assert_eq!(dom.idom(id("join")), Some(id("entry")));
+        assert_eq!(dom.idom(id("loop")), Some(id("right")));
+        let mut children = dom.children(id("entry")).to_vec();
+        children.sort();
+        assert_eq!(children, vec![id("left"), id("right"), id("join")]);
+        assert!(dom.dominates(id("right"), id("loop")));
+        assert!(dom.dominates(id("loop"), id("loop")));
+        assert!(!dom.strictly_dominates(id("loop"), id("loop")));
+        assert!(!dom.dominates(id("left"), id("join")));
+        assert!(!dom.is_reachable(id("dead")));
+        assert!(!dom.dominates(main.entry(), id("dead")));
+        assert_eq!(dom.dominators(id("loop")), vec![id("loop"), id("right"), id("entry"), main.entry()]);
+    }
+
+    #[test]
+    fn dominators_match_naive_fixpoint() {
+        let mut rng = Rng::new(0x5eed);
+        for _ in 0..200 {
+            let n = 1 + rng.below(12);
+            let text = random_function(&mut rng, n);
+            let cfg = BrilCFG::from_text(&text).unwrap();
+            let main = &cfg.functions[0];
+            let dom = Dominators::compute(main);
+            let naive = naive_dominators(main);
+            for (b, naive_doms) in naive.iter().enumerate() {
+                let fast = dom.dominators(b).into_iter().collect::<BTreeSet<_>>();
+                assert_eq!(&fast, naive_doms, "block {b} of\n{text}");
+                for a in 0..main.blocks.len() {
+                    assert_eq!(dom.dominates(a, b), naive_doms.contains(&a), "{a} dom {b} in\n{text}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dominance_frontiers() {
+        let bril_text = r#"@main(c: bool) {
+.entry:
+  br c .left .right;
+.left:
+  jmp .join;
+.right:
+  jmp .join;
+.join:
+  br c .entry .done;
+.done:
+  ret;
+}"#;
+        let cfg = BrilCFG::from_text(bril_text).unwrap();
+        let main = cfg.function("main").unwrap();
+        let id = |name| main.block_id(name).unwrap();
+        let df = Dominators::compute(main).frontiers(main);
+        assert_eq!(df[id("left")], vec![id("join")]);
+        assert_eq!(df[id("right")], vec![id("join")]);
+        assert_eq!(df[id("join")], vec![id("entry")]);
+        assert_eq!(df[id("entry")], vec![id("entry")]);
+        assert!(df[id("done")].is_empty());
+    }
+
+    #[test]
+    fn post_dominators() {
+        let bril_text = r#"@main(c: bool) {
+.entry:
+  br c .a .b;
+.a:
+  br c .ret1 .ret2;
+.ret1:
+  ret;
+.ret2:
+  ret;
+.b:
+  br c .spin .ret2;
+.spin:
+  jmp .spin;
+}"#;
+        let cfg = BrilCFG::from_text(bril_text).unwrap();
+        let main = cfg.function("main").unwrap();
+        let id = |name| main.block_id(name).unwrap();
+        let pdom = PostDominators::compute(main);
+        let exit = pdom.virtual_exit();
+        assert_eq!(exit, main.blocks.len());
+        // two returns and the infinite loop are all joined at the virtual exit
+        let mut exits = pdom.exits().to_vec();
+        exits.sort();
+        assert_eq!(exits, vec![id("ret1"), id("ret2"), id("spin")]);
+        assert_eq!(pdom.ipdom(id("a")), None);
+        assert_eq!(pdom.ipdom(id("entry")), None);
+        assert_eq!(pdom.ipdom(main.entry()), Some(id("entry")));
+        assert!(pdom.post_dominates(id("ret2"), id("ret2")));
+        assert!(!pdom.post_dominates(id("ret2"), id("b")));
+        assert!(pdom.tree().is_reachable(id("spin")));
+
+        let pdf = pdom.frontiers(main);
+        assert_eq!(pdf[id("ret1")], vec![id("a")]);
+        assert_eq!(pdf[id("ret2")], vec![id("a"), id("b")]);
+        assert_eq!(pdf[id("a")], vec![id("entry")]);
+        assert!(pdf[id("entry")].is_empty());
+    }
+
+    #[test]
+    fn frontiers_and_post_dominators_match_naive() {
+        let mut rng = Rng::new(0xf00d);
+        for _ in 0..200 {
+            let n = 1 + rng.below(12);
+            let text = random_function(&mut rng, n);
+            let cfg = BrilCFG::from_text(&text).unwrap();
+            let main = &cfg.functions[0];
+            let len = main.blocks.len();
+
+            let dom = Dominators::compute(main);
+            let df = dom.frontiers(main);
+            for (a, frontier) in df.iter().enumerate() {
+                let naive = (0..len)
+                    .filter(|b| {
+                        main.blocks[*b].preds().iter().any(|p| dom.dominates(a, *p)) && !dom.strictly_dominates(a, *b)
+                    })
+                    .collect::<Vec<_>>();
+                assert_eq!(frontier, &naive, "frontier of {a} in\n{text}");
+            }
+
+            // naive post-dominators on the cfg extended with the virtual exit
+            let pdom = PostDominators::compute(main);
+            let exit = pdom.virtual_exit();
+            let succs = |b: usize| {
+                let mut succs = main.blocks[b].succs().to_vec();
+                if pdom.exits().contains(&b) {
+                    succs.push(exit);
+                }
+                succs
+            };
+            let nodes = (0..=len).filter(|b| pdom.tree().is_reachable(*b)).collect::<BTreeSet<_>>();
+            assert!(main.reverse_postorder().iter().all(|b| nodes.contains(b)));
+            let mut pdoms = vec![nodes.clone(); len + 1];
+            pdoms[exit] = BTreeSet::from([exit]);
+            let mut changed = true;
+            while changed {
+                changed = false;
+                for &b in nodes.iter().filter(|b| **b != exit) {
+                    let mut new = nodes.clone();
+                    for s in succs(b).into_iter().filter(|s| nodes.contains(s)) {
+                        new = new.intersection(&pdoms[s]).copied().collect();
+                    }
+                    new.insert(b);
+                    if new != pdoms[b] {
+                        pdoms[b] = new;
+                        changed = true;
+                    }
+                }
+            }
+            for &b in &nodes {
+                for &a in &nodes {
+                    assert_eq!(pdom.post_dominates(a, b), pdoms[b].contains(&a), "{a} pdom {b} in\n{text}");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+// graphviz export of the cfg, e.g. `cfg --emit=dot prog.json | dot -Tpdf`
+use std::fmt::Write;
+
+use crate::{
+    cfg::{BlockId, BrilCFG, FunctionCFG},
+    parser::{Instr, Opcode},
+};
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// label of the edge `from -> to`, only branches get one
+fn edge_label(func: &FunctionCFG, from: BlockId, to: BlockId) -> Option<&'static str> {
+    let Some(Instr::Instruction { op: Opcode::br, labels: Some(labels), .. }) = func.blocks[from].instrs().last() else {
+        return None;
+    };
+    let target = func.blocks[to].name();
+    let on_true = labels.first().is_some_and(|l| l == target);
+    let on_false = labels.get(1).is_some_and(|l| l == target);
+    match (on_true, on_false) {
+        (true, true) => Some("true/false"),
+        (true, false) => Some("true"),
+        (false, true) => Some("false"),
+        (false, false) => None,
+    }
+}
+
+impl BrilCFG {
+    pub fn to_dot(&self) -> String {
+        self.to_dot_with(|_, _| vec![])
+    }
+
+    // `annotate` returns extra lines shown under each block, e.g. the
+    // dominators or live variables of the block
+    pub fn to_dot_with<F>(&self, annotate: F) -> String
+    where
+        F: Fn(&FunctionCFG, BlockId) -> Vec<String>,
+    {
+        let mut out = String::new();
+        writeln!(out, "digraph cfg {{").unwrap();
+        writeln!(out, "  node [shape=box, fontname=monospace];").unwrap();
+        for (f, func) in self.functions.iter().enumerate() {
+            writeln!(out, "  subgraph cluster_{f} {{").unwrap();
+            writeln!(out, "    label=\"@{}\";", escape(&func.name)).unwrap();
+            for (id, block) in func.blocks.iter().enumerate() {
+                // `\l` ends a left aligned line
+                let mut label = format!("{}:\\l", escape(block.name()));
+                for instr in block.instrs() {
+                    write!(label, "  {}\\l", escape(&instr.to_string())).unwrap();
+                }
+                let notes = annotate(func, id);
+                if !notes.is_empty() {
+                    label.push_str("--\\l");
+                    for note in notes {
+                        write!(label, "{}\\l", escape(&note)).unwrap();
+                    }
+                }
+                writeln!(out, "    f{f}_b{id} [label=\"{label}\"];").unwrap();
+            }
+            for (from, to) in func.edges() {
+                match edge_label(func, from, to) {
+                    Some(label) => writeln!(out, "    f{f}_b{from} -> f{f}_b{to} [label=\"{label}\"];").unwrap(),
+                    None => writeln!(out, "    f{f}_b{from} -> f{f}_b{to};").unwrap(),
+                }
+            }
+            writeln!(out, "  }}").unwrap();
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dot_export() {
+        let bril_text = r#"@main(c: bool) {
+  br c .then .else;
+.then:
+  print c;
+.else:
+  ret;
+}
+
+@f {
+  ret;
+}
+"#;
+        let cfg = BrilCFG::from_text(bril_text).unwrap();
+        let dot = cfg.to_dot_with(|func, id| vec![format!("{} #{id}", func.name)]);
+        println!("{dot}");
+        assert!(dot.starts_with("digraph cfg {"));
+        assert!(dot.contains("subgraph cluster_0 {\n    label=\"@main\";"));
+        assert!(dot.contains("subgraph cluster_1 {\n    label=\"@f\";"));
+        assert!(dot.contains("f0_b1 [label=\"then:\\l  print c;\\l--\\lmain #1\\l\"];"));
+        assert!(dot.contains("f0_b0 -> f0_b1 [label=\"true\"];"));
+        assert!(dot.contains("f0_b0 -> f0_b2 [label=\"false\"];"));
+        assert!(dot.contains("f0_b1 -> f0_b2;"));
+    }
+}
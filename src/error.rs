@@ -0,0 +1,72 @@
+use std::fmt::{self, Display};
+
+use crate::parser::ParseError;
+
+#[derive(Debug)]
+pub enum Error {
+    // bril text or json that cannot be read
+    Parse(ParseError),
+    // blocks or instructions that do not form a valid cfg
+    MalformedCfg(String),
+    // a variable that is used but never defined
+    UnknownVariable(String),
+    // a pass pipeline that is invalid or does not converge
+    Pipeline(String),
+    // reading the input or writing the output failed
+    Io(std::io::Error),
+    // the interpreter hit an error in the program it runs
+    Runtime(String),
+    // a function that breaks one of the invariants of ssa form
+    InvalidSsa(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse(e) => write!(f, "parse error at {e}"),
+            Error::MalformedCfg(msg) => write!(f, "malformed cfg: {msg}"),
+            Error::UnknownVariable(var) => write!(f, "unknown variable `{var}`"),
+            Error::Pipeline(msg) => write!(f, "pass pipeline: {msg}"),
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Runtime(msg) => write!(f, "runtime error: {msg}"),
+            Error::InvalidSsa(msg) => write!(f, "invalid ssa: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        // serde_json appends the position itself, keep it only once
+        let msg = e.to_string();
+        let msg = msg.split(" at line ").next().unwrap_or_default();
+        Error::Parse(ParseError {
+            line: e.line(),
+            col: e.column(),
+            msg: msg.to_string(),
+        })
+    }
+}
@@ -0,0 +1,789 @@
+// induction variables of natural loops, and strength reduction of the
+// multiplications computed from them
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::{
+    cfg::{BlockId, BrilCFG, FunctionCFG},
+    constprop::{ConstantPropagation, Constants, Value},
+    dataflow::{self, Analysis, Site},
+    dominators::Dominators,
+    error::Result,
+    liveness::Liveness,
+    loops::{Loop, LoopForest, LoopId},
+    parser::{Instr, Literal, Opcode, Type},
+    pass::{AnalysisCache, Granularity, Pass},
+    reaching::{Def, ReachingDefinitions},
+    ssa::{fresh_var, is_phi, var_names},
+};
+
+// a value that doesn't change while the loop runs
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Operand {
+    Const(i64),
+    Var(String),
+}
+
+impl Operand {
+    // only what can be known without emitting code, bril ints wrap
+    fn mul(&self, other: &Operand) -> Option<Operand> {
+        use Operand::*;
+        match (self, other) {
+            (Const(a), Const(b)) => Some(Const(a.wrapping_mul(*b))),
+            (Const(0), _) | (_, Const(0)) => Some(Const(0)),
+            (Const(1), x) | (x, Const(1)) => Some(x.clone()),
+            _ => None,
+        }
+    }
+
+    fn add(&self, other: &Operand) -> Option<Operand> {
+        use Operand::*;
+        match (self, other) {
+            (Const(a), Const(b)) => Some(Const(a.wrapping_add(*b))),
+            (Const(0), x) | (x, Const(0)) => Some(x.clone()),
+            _ => None,
+        }
+    }
+
+    fn neg(&self) -> Option<Operand> {
+        match self {
+            Operand::Const(a) => Some(Operand::Const(a.wrapping_neg())),
+            Operand::Var(_) => None,
+        }
+    }
+}
+
+// `scale * basic + offset`, with `basic` a basic induction variable
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Linear {
+    pub basic: String,
+    pub scale: Operand,
+    pub offset: Operand,
+}
+
+// a variable whose only assignment in the loop is `var = add var step`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicInduction {
+    pub var: String,
+    pub update: Site,
+    pub step: Operand,
+}
+
+pub struct InductionVariables {
+    pub basic: Vec<BasicInduction>,
+    // the value assigned at a site of the loop as a function of the current
+    // value of a basic induction variable
+    pub derived: BTreeMap<Site, Linear>,
+    reaching: ReachingDefinitions,
+}
+
+impl InductionVariables {
+    pub fn compute(func: &FunctionCFG, l: &Loop) -> Self {
+        let mut ivs = Self {
+            basic: vec![],
+            derived: BTreeMap::new(),
+            reaching: ReachingDefinitions::compute(func),
+        };
+        let mut defs_in_loop: HashMap<&str, Vec<Site>> = HashMap::new();
+        for block in &l.blocks {
+            for (index, instr) in func.blocks[*block].instrs().iter().enumerate() {
+                if let Instr::Instruction { dest: Some(dest), .. } = instr {
+                    defs_in_loop.entry(dest).or_default().push(Site { block: *block, index });
+                }
+            }
+        }
+
+        let mut vars = defs_in_loop.keys().copied().collect::<Vec<_>>();
+        vars.sort();
+        for var in vars {
+            let [update] = defs_in_loop[var][..] else {
+                continue;
+            };
+            // the value before the loop gets read in the preheader
+            if !ivs.defined_before(l, var) {
+                continue;
+            }
+            let Instr::Instruction { op, args: Some(args), .. } = &func.blocks[update.block].instrs[update.index] else {
+                continue;
+            };
+            let step = match (op, args.as_slice()) {
+                (Opcode::add, [a, b]) if a == var && b != var => ivs.invariant(func, l, update, b),
+                (Opcode::add, [a, b]) if b == var && a != var => ivs.invariant(func, l, update, a),
+                (Opcode::sub, [a, b]) if a == var && b != var => {
+                    ivs.invariant(func, l, update, b).and_then(|step| step.neg())
+                }
+                _ => None,
+            };
+            if let Some(step) = step {
+                ivs.basic.push(BasicInduction { var: var.to_string(), update, step });
+            }
+        }
+
+        // the operands of a derived variable are read in the same block as
+        // they are computed, so the basic variable can't change in between
+        for block in &l.blocks {
+            for (index, instr) in func.blocks[*block].instrs().iter().enumerate() {
+                let site = Site { block: *block, index };
+                let Instr::Instruction { op, dest: Some(_), args: Some(args), .. } = instr else {
+                    continue;
+                };
+                if ivs.basic.iter().any(|basic| basic.update == site) {
+                    continue;
+                }
+                let linear = match (op, args.as_slice()) {
+                    (Opcode::id, [x]) => ivs.linear(site, x),
+                    (Opcode::mul | Opcode::add | Opcode::sub, [a, b]) => {
+                        let (x, k, swapped) = match (ivs.linear(site, a), ivs.linear(site, b)) {
+                            (Some(x), None) => (x, ivs.invariant(func, l, site, b), false),
+                            (None, Some(x)) => (x, ivs.invariant(func, l, site, a), true),
+                            _ => continue,
+                        };
+                        k.and_then(|k| match op {
+                            Opcode::mul => Some(Linear {
+                                basic: x.basic,
+                                scale: x.scale.mul(&k)?,
+                                offset: x.offset.mul(&k)?,
+                            }),
+                            Opcode::add => Some(Linear { offset: x.offset.add(&k)?, ..x }),
+                            // `k - x` would need a negated scale
+                            _ if swapped => None,
+                            _ => Some(Linear { offset: x.offset.add(&k.neg()?)?, ..x }),
+                        })
+                    }
+                    _ => None,
+                };
+                if let Some(linear) = linear {
+                    ivs.derived.insert(site, linear);
+                }
+            }
+        }
+        ivs
+    }
+
+    pub fn basic(&self, var: &str) -> Option<&BasicInduction> {
+        self.basic.iter().find(|basic| basic.var == var)
+    }
+
+    // the value of `var` read at `site` if it's the same on every iteration:
+    // a constant, or a variable only assigned before the loop
+    pub fn invariant(&self, func: &FunctionCFG, l: &Loop, site: Site, var: &str) -> Option<Operand> {
+        let defs = self.reaching.defs_of(site, var)?;
+        if let [Def::Instr(def)] = defs.iter().collect::<Vec<_>>()[..] {
+            if let Instr::Instruction { op: Opcode::cst, typ: Some(Type::int), value: Some(Literal::Number(n)), .. } =
+                &func.blocks[def.block].instrs()[def.index]
+            {
+                return Some(Operand::Const(*n));
+            }
+        }
+        let outside = defs.iter().all(|def| !matches!(def, Def::Instr(def) if l.contains(def.block)));
+        (!defs.is_empty() && outside && self.defined_before(l, var)).then(|| Operand::Var(var.to_string()))
+    }
+
+    fn defined_before(&self, l: &Loop, var: &str) -> bool {
+        self.reaching
+            .block_in(l.header)
+            .get(var)
+            .is_some_and(|defs| !defs.is_empty() && !defs.contains(&Def::Undefined))
+    }
+
+    fn linear(&self, site: Site, var: &str) -> Option<Linear> {
+        if self.basic(var).is_some() {
+            return Some(Linear {
+                basic: var.to_string(),
+                scale: Operand::Const(1),
+                offset: Operand::Const(0),
+            });
+        }
+        let [Def::Instr(def)] = self.reaching.defs_of(site, var)?.iter().collect::<Vec<_>>()[..] else {
+            return None;
+        };
+        let linear = self.derived.get(def)?;
+        let update = self.basic(&linear.basic)?.update;
+        let in_between = update.block == site.block && def.index < update.index && update.index < site.index;
+        (def.block == site.block && def.index < site.index && !in_between).then(|| linear.clone())
+    }
+}
+
+// a loop with a single exit test on a basic induction variable against a
+// constant, all of them known before the loop starts
+#[derive(Debug, PartialEq)]
+pub struct CountedLoop {
+    // the block with the exit test, either the header or the only latch
+    pub exiting: BlockId,
+    // the comparison the exit test branches on
+    pub test: Site,
+    pub var: String,
+    pub bound: i64,
+    pub init: i64,
+    pub step: i64,
+    // the value of `var` the exit test sees first, `first + step * trips`
+    // is the one it leaves on
+    pub first: i64,
+    // passes through the loop that don't leave it, the exit test runs once
+    // more. for a loop tested at the top that's how often the body runs
+    pub trips: i64,
+    // where the exit test goes to stay in the loop and to leave it
+    pub stay: String,
+    pub leave: String,
+}
+
+impl FunctionCFG {
+    pub fn counted_loop(&self, forest: &LoopForest, id: LoopId) -> Option<CountedLoop> {
+        let l = forest.get(id);
+        let [latch] = l.latches[..] else {
+            return None;
+        };
+        let [exiting] = l.exiting[..] else {
+            return None;
+        };
+        if exiting != l.header && exiting != latch {
+            return None;
+        }
+        let instrs = self.blocks[exiting].instrs();
+        let Some(Instr::Instruction { op: Opcode::br, args: Some(cond), labels: Some(labels), .. }) = instrs.last() else {
+            return None;
+        };
+        let (Some(cond), Some(on_true)) = (cond.first(), labels.first().and_then(|label| self.block_id(label))) else {
+            return None;
+        };
+        let stay_on_true = l.contains(on_true);
+        let [stay, leave] = match labels.as_slice() {
+            [on_true, on_false] if stay_on_true => [on_true, on_false],
+            [on_true, on_false] => [on_false, on_true],
+            _ => return None,
+        };
+        if self.block_id(leave).is_none_or(|leave| l.contains(leave)) {
+            return None;
+        }
+
+        // the comparison the branch reads
+        let (index, op, args) = instrs.iter().enumerate().rev().find_map(|(index, instr)| match instr {
+            Instr::Instruction { op, dest: Some(dest), args: Some(args), .. } if dest == cond => Some((index, op, args)),
+            _ => None,
+        })?;
+        let (Opcode::lt | Opcode::le | Opcode::gt | Opcode::ge | Opcode::eq, [a, b]) = (op, args.as_slice()) else {
+            return None;
+        };
+        let site = Site { block: exiting, index };
+
+        let ivs = InductionVariables::compute(self, l);
+        let results = dataflow::solve(&ConstantPropagation, self);
+        let mut before: Constants = None;
+        for pred in self.blocks[l.header].preds().iter().filter(|pred| !l.contains(**pred)) {
+            ConstantPropagation.meet(&mut before, results.block_out(*pred));
+        }
+        let known = |operand: Operand| match operand {
+            Operand::Const(n) => Some(n),
+            Operand::Var(var) => match before.as_ref()?.get(&var) {
+                Some(Value::Const(Literal::Number(n))) => Some(*n),
+                _ => None,
+            },
+        };
+        let (basic, bound, swapped) = match (ivs.basic(a), ivs.basic(b)) {
+            (Some(basic), None) => (basic, ivs.invariant(self, l, site, b)?, false),
+            (None, Some(basic)) => (basic, ivs.invariant(self, l, site, a)?, true),
+            _ => return None,
+        };
+        let bound = known(bound)?;
+        let step = known(basic.step.clone()).filter(|step| *step != 0)?;
+        let init = known(Operand::Var(basic.var.clone()))?;
+
+        // the update has to run exactly once per pass
+        let update = basic.update;
+        let dom = Dominators::compute(self);
+        if forest.innermost(update.block) != Some(id) || !dom.dominates(update.block, latch) {
+            return None;
+        }
+        // whether the exit test sees the variable already updated
+        let updated = if update.block == exiting { update.index < index } else { dom.dominates(update.block, exiting) };
+        let first = if updated { init.checked_add(step)? } else { init };
+        Some(CountedLoop {
+            exiting,
+            test: site,
+            var: basic.var.clone(),
+            bound,
+            init,
+            step,
+            first,
+            trips: trip_count(op, swapped, stay_on_true, first, step, bound)?,
+            stay: stay.clone(),
+            leave: leave.clone(),
+        })
+    }
+}
+
+// how often the exit test on `first`, `first + step`, ... stays in the loop
+// before it leaves. none if the variable wraps around before that, then its
+// values aren't in order anymore
+fn trip_count(op: &Opcode, swapped: bool, stay_on_true: bool, first: i64, step: i64, bound: i64) -> Option<i64> {
+    let (first, step, bound) = (i128::from(first), i128::from(step), i128::from(bound));
+    let trips = match op {
+        Opcode::eq if (first == bound) != stay_on_true => 0,
+        // the next value is a different one already
+        Opcode::eq if stay_on_true => 1,
+        // stays until the variable hits the bound
+        Opcode::eq => {
+            let distance = bound - first;
+            (distance % step == 0 && distance / step > 0).then_some(distance / step)?
+        }
+        _ => {
+            // the values the comparison holds for go up to or down from a
+            // limit, `bound < i` is `i > bound`
+            let (up_to, limit) = match (op, swapped) {
+                (Opcode::lt, false) | (Opcode::gt, true) => (true, bound - 1),
+                (Opcode::le, false) | (Opcode::ge, true) => (true, bound),
+                (Opcode::gt, false) | (Opcode::lt, true) => (false, bound + 1),
+                _ => (false, bound),
+            };
+            // and the ones that stay in the loop
+            let (up_to, limit) = match (stay_on_true, up_to) {
+                (true, _) => (up_to, limit),
+                (false, true) => (false, limit + 1),
+                (false, false) => (true, limit - 1),
+            };
+            match (up_to, step > 0) {
+                (true, _) if first > limit => 0,
+                (false, _) if first < limit => 0,
+                (true, true) => (limit - first) / step + 1,
+                (false, false) => (first - limit) / -step + 1,
+                // moves away from the limit until it wraps around
+                _ => return None,
+            }
+        }
+    };
+    // the value the test leaves on comes before any wrap around, so do all
+    // the ones in between
+    i64::try_from(first + step * trips).ok()?;
+    i64::try_from(trips).ok()
+}
+
+pub struct StrengthReduction;
+
+impl Pass for StrengthReduction {
+    fn name(&self) -> &'static str {
+        "strength"
+    }
+
+    fn granularity(&self) -> Granularity {
+        Granularity::Function
+    }
+
+    fn run_on_function(&mut self, func: &mut FunctionCFG, _cache: &mut AnalysisCache) -> Result<bool> {
+        func.strength_reduce()
+    }
+}
+
+impl BrilCFG {
+    pub fn strength_reduce(&mut self) -> Result<()> {
+        for func in self.functions.iter_mut() {
+            func.strength_reduce()?;
+        }
+        Ok(())
+    }
+}
+
+fn instr(op: Opcode, dest: &str, args: Vec<String>, typ: Type) -> Instr {
+    Instr::Instruction {
+        op,
+        dest: Some(dest.to_string()),
+        typ: Some(typ),
+        args: Some(args),
+        funcs: None,
+        labels: None,
+        value: None,
+    }
+}
+
+// the code that goes into the preheader, with fresh variables named after
+// the ones they replace
+struct Preheader {
+    names: HashSet<String>,
+    consts: HashMap<i64, String>,
+    instrs: Vec<Instr>,
+}
+
+impl Preheader {
+    fn fresh(&mut self, base: &str) -> String {
+        fresh_var(&mut self.names, base)
+    }
+
+    // the name of a variable holding `operand`, each constant gets one
+    fn operand(&mut self, operand: &Operand, base: &str) -> String {
+        match operand {
+            Operand::Var(var) => var.clone(),
+            Operand::Const(n) => {
+                if let Some(var) = self.consts.get(n) {
+                    return var.clone();
+                }
+                let var = self.fresh(base);
+                self.instrs.push(Instr::new_const_instr(&var, Literal::Number(*n), Type::int));
+                self.consts.insert(*n, var.clone());
+                var
+            }
+        }
+    }
+
+    // `dest = scale * x + offset`
+    fn linear(&mut self, dest: &str, linear: &Linear, x: &str, base: &str) {
+        if linear.scale == Operand::Const(1) {
+            self.instrs.push(instr(Opcode::id, dest, vec![x.to_string()], Type::int));
+        } else {
+            let scale = self.operand(&linear.scale, base);
+            self.instrs.push(instr(Opcode::mul, dest, vec![x.to_string(), scale], Type::int));
+        }
+        if linear.offset != Operand::Const(0) {
+            let offset = self.operand(&linear.offset, base);
+            self.instrs.push(instr(Opcode::add, dest, vec![dest.to_string(), offset], Type::int));
+        }
+    }
+}
+
+impl FunctionCFG {
+    // replace multiplications of induction variables by a variable that
+    // grows by a constant amount every iteration, and the exit test of a
+    // counted loop by a test on these where that can't overflow. works out
+    // of ssa form, where a loop carried value is a plain reassignment.
+    // returns whether anything changed
+    pub fn strength_reduce(&mut self) -> Result<bool> {
+        if self.blocks.iter().flat_map(|block| block.instrs()).any(is_phi) {
+            return Ok(false);
+        }
+        let forest = LoopForest::compute(self);
+        let headers = forest
+            .postorder()
+            .into_iter()
+            .map(|id| self.blocks[forest.get(id).header].name.clone())
+            .collect::<Vec<_>>();
+        let mut changed = false;
+        for header in headers {
+            changed |= self.reduce_loop(&header)?;
+        }
+        Ok(changed)
+    }
+
+    fn reduce_loop(&mut self, header: &str) -> Result<bool> {
+        let forest = LoopForest::compute(self);
+        let Some(header) = self.block_id(header) else {
+            return Ok(false);
+        };
+        let Some(id) = forest.innermost(header).filter(|_| header != self.entry()) else {
+            return Ok(false);
+        };
+        let l = forest.get(id);
+        let ivs = InductionVariables::compute(self, l);
+
+        // multiplications computing the same function share a variable
+        let mut families: Vec<(Linear, Vec<Site>)> = vec![];
+        for (site, linear) in &ivs.derived {
+            if !matches!(&self.blocks[site.block].instrs[site.index], Instr::Instruction { op: Opcode::mul, .. }) {
+                continue;
+            }
+            match families.iter_mut().find(|(other, _)| other == linear) {
+                Some((_, sites)) => sites.push(*site),
+                None => families.push((linear.clone(), vec![*site])),
+            }
+        }
+        if families.is_empty() {
+            return Ok(false);
+        }
+
+        let mut preheader = Preheader {
+            names: var_names(self),
+            consts: HashMap::new(),
+            instrs: vec![],
+        };
+        let mut replaced: HashMap<Site, Instr> = HashMap::new();
+        let mut after: HashMap<Site, Vec<Instr>> = HashMap::new();
+        // a reduced variable with a positive constant scale for each basic
+        // variable, for the test replacement
+        let mut reduced: HashMap<String, (String, Linear, String)> = HashMap::new();
+        for (linear, sites) in families {
+            let Instr::Instruction { dest: Some(first), .. } = &self.blocks[sites[0].block].instrs[sites[0].index] else {
+                continue;
+            };
+            let first = &first.clone();
+            let var = preheader.fresh(first);
+            let basic = ivs.basic(&linear.basic).unwrap();
+            preheader.linear(&var, &linear, &basic.var, first);
+            let step = match linear.scale.mul(&basic.step) {
+                Some(step) => preheader.operand(&step, first),
+                None => {
+                    let step = preheader.fresh(first);
+                    let scale = preheader.operand(&linear.scale, first);
+                    let basic_step = preheader.operand(&basic.step, first);
+                    preheader.instrs.push(instr(Opcode::mul, &step, vec![scale, basic_step], Type::int));
+                    step
+                }
+            };
+            after
+                .entry(basic.update)
+                .or_default()
+                .push(instr(Opcode::add, &var, vec![var.clone(), step], Type::int));
+            for site in sites {
+                if let Instr::Instruction { dest: Some(dest), .. } = &self.blocks[site.block].instrs[site.index] {
+                    replaced.insert(site, instr(Opcode::id, dest, vec![var.clone()], Type::int));
+                }
+            }
+            if matches!(linear.scale, Operand::Const(scale) if scale > 0) {
+                reduced.entry(linear.basic.clone()).or_insert((var, linear, first.clone()));
+            }
+        }
+
+        // linear function test replacement: the exit test `i < n` becomes
+        // `a*i + b < a*n + b`. bril ints wrap, so only when none of the values
+        // the test sees maps out of range, which takes a loop with a known
+        // count. otherwise the test keeps reading the basic variable
+        if let Some(counted) = self.counted_loop(&forest, id) {
+            let reduced = reduced.get(&counted.var);
+            let test = &self.blocks[counted.test.block].instrs[counted.test.index];
+            if let (Some((var, linear, base)), Instr::Instruction { op, dest: Some(dest), args: Some(args), .. }) =
+                (reduced, test)
+            {
+                let map = |x: i64| match (&linear.scale, &linear.offset) {
+                    (Operand::Const(a), Operand::Const(b)) => a.checked_mul(x)?.checked_add(*b),
+                    _ => None,
+                };
+                // monotonic, so the values in between fit as well
+                let last = counted.first + counted.step * counted.trips;
+                if let (Some(_), Some(_), Some(bound)) = (map(counted.first), map(last), map(counted.bound)) {
+                    let bound = preheader.operand(&Operand::Const(bound), base);
+                    let args = if args[0] == counted.var { vec![var.clone(), bound] } else { vec![bound, var.clone()] };
+                    replaced.insert(
+                        counted.test,
+                        Instr::Instruction {
+                            op: op.clone(),
+                            dest: Some(dest.clone()),
+                            typ: Some(Type::bool),
+                            args: Some(args),
+                            funcs: None,
+                            labels: None,
+                            value: None,
+                        },
+                    );
+                }
+            }
+        }
+
+        // a basic variable that only feeds its own update is dead now
+        let live = Liveness::compute(self);
+        let mut removed = HashSet::new();
+        for basic in &ivs.basic {
+            let read = l.blocks.iter().any(|block| {
+                self.blocks[*block].instrs.iter().enumerate().any(|(index, instr)| {
+                    let site = Site { block: *block, index };
+                    let instr = replaced.get(&site).unwrap_or(instr);
+                    site != basic.update
+                        && matches!(instr, Instr::Instruction { args: Some(args), .. } if args.contains(&basic.var))
+                })
+            });
+            if !read && l.exits.iter().all(|exit| !live.block_in(*exit).contains(&basic.var)) {
+                removed.insert(basic.update);
+            }
+        }
+
+        for block in &l.blocks {
+            let instrs = std::mem::take(&mut self.blocks[*block].instrs);
+            for (index, instr) in instrs.into_iter().enumerate() {
+                let site = Site { block: *block, index };
+                if !removed.contains(&site) {
+                    let instr = replaced.remove(&site).unwrap_or(instr);
+                    self.blocks[*block].instrs.push(instr);
+                }
+                self.blocks[*block].instrs.extend(after.remove(&site).into_iter().flatten());
+            }
+        }
+        self.insert_preheader(l, preheader.instrs)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn induction_variables() {
+        let bril_text = r#"@main(n: int, k: int) {
+  i: int = const 0;
+  m: int = const 10;
+  one: int = const 1;
+.loop:
+  four: int = const 4;
+  a: int = mul i four;
+  b: int = add a one;
+  c: int = sub b n;
+  d: int = mul k i;
+  e: int = mul d k;
+  i: int = add i one;
+  f: int = mul a n;
+  m: int = sub m one;
+  g: int = mul i m;
+  n: int = id n;
+  cond: bool = lt i n;
+  br cond .loop .done;
+.done:
+  print b;
+}"#;
+        let cfg = BrilCFG::from_text(bril_text).unwrap();
+        let main = cfg.function("main").unwrap();
+        let forest = LoopForest::compute(main);
+        let l = forest.get(0);
+        let ivs = InductionVariables::compute(main, l);
+        let site = |index| Site { block: l.header, index };
+        let linear = |basic: &str, scale, offset| Linear { basic: basic.to_string(), scale, offset };
+        use Operand::*;
+
+        assert_eq!(
+            ivs.basic,
+            vec![
+                BasicInduction { var: "i".to_string(), update: site(6), step: Const(1) },
+                BasicInduction { var: "m".to_string(), update: site(8), step: Const(-1) },
+            ]
+        );
+        assert_eq!(ivs.derived[&site(1)], linear("i", Const(4), Const(0)));
+        assert_eq!(ivs.derived[&site(2)], linear("i", Const(4), Const(1)));
+        // `n` is assigned in the loop, so it's not invariant
+        assert!(!ivs.derived.contains_key(&site(3)));
+        assert_eq!(ivs.derived[&site(4)], linear("i", Var("k".to_string()), Const(0)));
+        // `k * k` would need an instruction of its own
+        assert!(!ivs.derived.contains_key(&site(5)));
+        // `i` changed since `a` was computed
+        assert!(!ivs.derived.contains_key(&site(7)));
+        // the product of two induction variables
+        assert!(!ivs.derived.contains_key(&site(9)));
+    }
+
+    fn run(cfg: &BrilCFG, n: i64) -> String {
+        cfg.interpret(&[Literal::Number(n)]).unwrap()
+    }
+
+    #[test]
+    fn strength_reduction() {
+        let bril_text = r#"@main(n: int) {
+  i: int = const 0;
+  one: int = const 1;
+  four: int = const 4;
+  s: int = const 0;
+.loop:
+  c: bool = lt i n;
+  br c .body .done;
+.body:
+  j: int = mul i four;
+  k: int = add j one;
+  s: int = add s k;
+  i: int = add i one;
+  jmp .loop;
+.done:
+  print s;
+}"#;
+        let original = BrilCFG::from_text(bril_text).unwrap();
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        assert!(cfg.functions[0].strength_reduce().unwrap());
+        let bril_txt = cfg.to_text();
+        println!("bril_txt: {bril_txt}");
+        // `4 * n` wraps around to 4 for the last one
+        for n in [0, 1, 5, 17, -4611686018427387903] {
+            assert_eq!(run(&original, n), run(&cfg, n));
+        }
+        assert!(bril_txt.contains("  j.tmp1: int = const 4;\n  j.tmp0: int = mul i j.tmp1;\n.loop:"));
+        assert!(bril_txt.contains("  j: int = id j.tmp0;"));
+        assert!(bril_txt.contains("  j.tmp0: int = add j.tmp0 j.tmp1;\n  jmp .loop;"));
+        // nothing is known about `n`, so the test stays and so does `i`
+        assert!(bril_txt.contains("  c: bool = lt i n;"));
+        assert!(bril_txt.contains("  i: int = add i one;"));
+
+        // with a constant bound the test and the body only use the new
+        // variable, so `i` is gone
+        let bril_text = bril_text.replace("@main(n: int) {", "@main {\n  n: int = const 17;");
+        let original = BrilCFG::from_text(&bril_text).unwrap();
+        let mut cfg = BrilCFG::from_text(&bril_text).unwrap();
+        assert!(cfg.functions[0].strength_reduce().unwrap());
+        let bril_txt = cfg.to_text();
+        println!("bril_txt: {bril_txt}");
+        assert_eq!(original.interpret(&[]).unwrap(), cfg.interpret(&[]).unwrap());
+        assert!(bril_txt.contains("  j.tmp2: int = const 68;\n.loop:"));
+        assert!(bril_txt.contains("  c: bool = lt j.tmp0 j.tmp2;"));
+        assert!(!bril_txt.contains("i: int = add i one;"));
+    }
+
+    #[test]
+    fn strength_reduction_wrapping_bound() {
+        // `4 * n` wraps around to 4, `j < 4 * n` would run the loop once
+        let bril_text = r#"@main {
+  n: int = const -4611686018427387903;
+  i: int = const 0;
+  one: int = const 1;
+  four: int = const 4;
+  s: int = const 0;
+.loop:
+  c: bool = lt i n;
+  br c .body .done;
+.body:
+  j: int = mul i four;
+  s: int = add s j;
+  i: int = add i one;
+  jmp .loop;
+.done:
+  print s;
+}"#;
+        let original = BrilCFG::from_text(bril_text).unwrap();
+        // `4 * n` is 4 again, `j == 4 * n` would leave after one pass
+        let eq = bril_text
+            .replace("lt i n", "eq i n")
+            .replace(".body .done", ".done .body")
+            .replace("-4611686018427387903", "4611686018427387905");
+        for bril_text in [bril_text.to_string(), eq] {
+            let mut cfg = BrilCFG::from_text(&bril_text).unwrap();
+            assert!(cfg.functions[0].strength_reduce().unwrap());
+            let bril_txt = cfg.to_text();
+            println!("bril_txt: {bril_txt}");
+            assert!(bril_txt.contains("  i: int = add i one;"));
+            assert!(bril_txt.contains(" i n;"));
+        }
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        cfg.strength_reduce().unwrap();
+        assert_eq!(original.interpret(&[]).unwrap(), "0\n");
+        assert_eq!(cfg.interpret(&[]).unwrap(), "0\n");
+    }
+
+    #[test]
+    fn strength_reduction_nested() {
+        let bril_text = r#"@main(n: int) {
+  one: int = const 1;
+  sum: int = const 0;
+  i: int = const 0;
+.outer:
+  ci: bool = lt i n;
+  br ci .outer_body .done;
+.outer_body:
+  j: int = const 0;
+.inner:
+  cj: bool = lt j n;
+  br cj .inner_body .inner_done;
+.inner_body:
+  row: int = mul i n;
+  idx: int = add row j;
+  sum: int = add sum idx;
+  j: int = add j one;
+  jmp .inner;
+.inner_done:
+  i: int = add i one;
+  jmp .outer;
+.done:
+  print sum i;
+}"#;
+        let original = BrilCFG::from_text(bril_text).unwrap();
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        assert!(cfg.functions[0].strength_reduce().unwrap());
+        let bril_txt = cfg.to_text();
+        println!("bril_txt: {bril_txt}");
+        for n in [0, 1, 3, 6] {
+            assert_eq!(run(&original, n), run(&cfg, n));
+        }
+        // `i * n` grows by `n` per iteration of the outer loop. `i` is printed
+        // after the loop and the scale isn't constant, so it stays
+        assert!(bril_txt.contains("  row: int = id row.tmp0;"));
+        assert!(bril_txt.contains("  row.tmp0: int = mul i n;"));
+        assert!(bril_txt.contains("  i: int = add i one;\n  row.tmp0: int = add row.tmp0 n;"));
+        assert!(bril_txt.contains("  ci: bool = lt i n;"));
+    }
+}
@@ -0,0 +1,293 @@
+// a small interpreter over the cfg, enough to check that a pass keeps the
+// output of a program the same
+use std::{collections::HashMap, fmt::Write};
+
+use crate::{
+    cfg::{BlockId, BrilCFG, FunctionCFG},
+    constprop::{const_value, fold},
+    error::{Error, Result},
+    parser::{Instr, Literal, Opcode},
+    ssa::UNDEFINED,
+};
+
+// a program that runs longer than this most likely never stops
+const DEFAULT_MAX_STEPS: usize = 1_000_000;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Literal(Literal),
+    // index of the allocation and offset into it
+    Ptr(usize, i64),
+}
+
+pub struct Interpreter<'a> {
+    cfg: &'a BrilCFG,
+    heap: Vec<Option<Vec<Option<Value>>>>,
+    steps: usize,
+    max_steps: usize,
+    // everything printed so far, also after an error
+    pub output: String,
+    // number of instructions executed, the cost measure for optimizations
+    pub dyn_instrs: usize,
+}
+
+fn runtime<T>(msg: String) -> Result<T> {
+    Err(Error::Runtime(msg))
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(cfg: &'a BrilCFG) -> Self {
+        Self {
+            cfg,
+            heap: vec![],
+            steps: 0,
+            max_steps: DEFAULT_MAX_STEPS,
+            output: String::new(),
+            dyn_instrs: 0,
+        }
+    }
+
+    pub fn with_max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    pub fn run_main(&mut self, args: &[Literal]) -> Result<()> {
+        let args = args.iter().cloned().map(Value::Literal).collect();
+        self.call("main", args)?;
+        Ok(())
+    }
+
+    fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Option<Value>> {
+        let Some(func) = self.cfg.function(name) else {
+            return runtime(format!("call to undefined function @{name}"));
+        };
+        let params = func.args.iter().flatten().collect::<Vec<_>>();
+        if params.len() != args.len() {
+            return runtime(format!("@{name} expects {} arguments, got {}", params.len(), args.len()));
+        }
+        let mut env = params.iter().map(|param| param.name.clone()).zip(args).collect::<HashMap<_, _>>();
+        self.run_function(func, &mut env)
+    }
+
+    fn run_function(&mut self, func: &FunctionCFG, env: &mut HashMap<String, Value>) -> Result<Option<Value>> {
+        let mut prev: Option<BlockId> = None;
+        let mut block = func.entry();
+        loop {
+            self.steps += 1;
+            if self.steps > self.max_steps {
+                return runtime(format!("gave up after {} steps", self.max_steps));
+            }
+            let mut next = func.blocks[block].succs().first().copied();
+            // the phis at the start of a block read their arguments all at once
+            let mut phis = vec![];
+            for instr in func.blocks[block].instrs() {
+                let Instr::Instruction { op, dest, typ, args, funcs, labels, value } = instr else {
+                    return runtime(format!("label inside block {}", func.blocks[block].name()));
+                };
+                self.dyn_instrs += 1;
+                if *op != Opcode::phi {
+                    Self::assign_phis(env, &mut phis);
+                }
+                let args = args.as_deref().unwrap_or_default();
+                let labels = labels.as_deref().unwrap_or_default();
+                let result = match op {
+                    Opcode::cst => {
+                        let Some(value) = value else {
+                            return runtime(format!("const without value: {instr}"));
+                        };
+                        Some(Value::Literal(const_value(typ, value)))
+                    }
+                    Opcode::phi => {
+                        let from = prev.map(|prev| func.blocks[prev].name());
+                        let Some(arg) = labels.iter().position(|label| Some(label.as_str()) == from).map(|i| &args[i]) else {
+                            return runtime(format!("phi without a label for the previous block: {instr}"));
+                        };
+                        let value = if arg == UNDEFINED { None } else { Some(self.get(env, arg)?) };
+                        if let Some(dest) = dest {
+                            phis.push((dest.clone(), value));
+                        }
+                        None
+                    }
+                    Opcode::jmp => {
+                        next = labels.first().and_then(|label| func.block_id(label));
+                        None
+                    }
+                    Opcode::br => {
+                        let cond = self.get(env, &args[0])?;
+                        let target = if cond == Value::Literal(Literal::Bool(true)) { &labels[0] } else { &labels[1] };
+                        next = func.block_id(target);
+                        None
+                    }
+                    Opcode::ret => {
+                        return args.first().map(|arg| self.get(env, arg)).transpose();
+                    }
+                    Opcode::call => {
+                        let values = args.iter().map(|arg| self.get(env, arg)).collect::<Result<Vec<_>>>()?;
+                        let callee = funcs.as_ref().and_then(|funcs| funcs.first()).map_or("", String::as_str);
+                        self.call(callee, values)?
+                    }
+                    Opcode::print => {
+                        let values = args.iter().map(|arg| self.get(env, arg)).collect::<Result<Vec<_>>>()?;
+                        let line = values.iter().map(Self::show).collect::<Vec<_>>().join(" ");
+                        writeln!(self.output, "{line}").unwrap();
+                        None
+                    }
+                    Opcode::nop => None,
+                    // copies pointers too
+                    Opcode::id => Some(self.get(env, &args[0])?),
+                    Opcode::alloc => {
+                        let size = match self.get(env, &args[0])? {
+                            Value::Literal(Literal::Number(size)) if size > 0 => size as usize,
+                            size => return runtime(format!("cannot allocate {size:?} elements")),
+                        };
+                        self.heap.push(Some(vec![None; size]));
+                        Some(Value::Ptr(self.heap.len() - 1, 0))
+                    }
+                    Opcode::free => {
+                        let (alloc, _) = self.pointer(env, &args[0])?;
+                        self.heap[alloc] = None;
+                        None
+                    }
+                    Opcode::store => {
+                        let value = self.get(env, &args[1])?;
+                        *self.cell(env, &args[0])? = Some(value);
+                        None
+                    }
+                    Opcode::load => match self.cell(env, &args[0])?.clone() {
+                        Some(value) => Some(value),
+                        None => return runtime(format!("load of uninitialized memory: {instr}")),
+                    },
+                    Opcode::ptradd => {
+                        let (alloc, offset) = self.pointer(env, &args[0])?;
+                        match self.get(env, &args[1])? {
+                            Value::Literal(Literal::Number(n)) => Some(Value::Ptr(alloc, offset.wrapping_add(n))),
+                            n => return runtime(format!("ptradd by {n:?}")),
+                        }
+                    }
+                    _ => {
+                        let mut operands = vec![];
+                        for arg in args {
+                            match self.get(env, arg)? {
+                                Value::Literal(value) => operands.push(value),
+                                value => return runtime(format!("`{op}` on {value:?}")),
+                            }
+                        }
+                        match fold(op, &operands) {
+                            Some(value) => Some(Value::Literal(value)),
+                            None if *op == Opcode::div => return runtime(format!("division by zero: {instr}")),
+                            None => return runtime(format!("bad operands for {instr}")),
+                        }
+                    }
+                };
+                if let (Some(dest), Some(result)) = (dest, result) {
+                    env.insert(dest.clone(), result);
+                }
+            }
+            Self::assign_phis(env, &mut phis);
+            // `next` is None after falling off the end of the function
+            let Some(next) = next else {
+                return Ok(None);
+            };
+            prev = Some(block);
+            block = next;
+        }
+    }
+
+    fn assign_phis(env: &mut HashMap<String, Value>, phis: &mut Vec<(String, Option<Value>)>) {
+        for (dest, value) in phis.drain(..) {
+            match value {
+                Some(value) => env.insert(dest, value),
+                None => env.remove(&dest),
+            };
+        }
+    }
+
+    fn get(&self, env: &HashMap<String, Value>, var: &str) -> Result<Value> {
+        env.get(var).cloned().ok_or_else(|| Error::UnknownVariable(var.to_string()))
+    }
+
+    fn pointer(&self, env: &HashMap<String, Value>, var: &str) -> Result<(usize, i64)> {
+        match self.get(env, var)? {
+            Value::Ptr(alloc, offset) if self.heap[alloc].is_some() => Ok((alloc, offset)),
+            value => runtime(format!("`{var}` is not a live pointer: {value:?}")),
+        }
+    }
+
+    fn cell(&mut self, env: &HashMap<String, Value>, var: &str) -> Result<&mut Option<Value>> {
+        let (alloc, offset) = self.pointer(env, var)?;
+        let cells = self.heap[alloc].as_mut().unwrap();
+        match usize::try_from(offset).ok().and_then(|offset| cells.get_mut(offset)) {
+            Some(cell) => Ok(cell),
+            None => runtime(format!("`{var}` points out of bounds")),
+        }
+    }
+
+    fn show(value: &Value) -> String {
+        match value {
+            Value::Literal(Literal::Float(f)) => format!("{f:.17}"),
+            Value::Literal(Literal::Number(n)) => n.to_string(),
+            Value::Literal(Literal::Bool(b)) => b.to_string(),
+            Value::Ptr(alloc, offset) => format!("ptr({alloc}, {offset})"),
+        }
+    }
+}
+
+impl BrilCFG {
+    // run `main` with `args` and return what it printed
+    pub fn interpret(&self, args: &[Literal]) -> Result<String> {
+        let mut interp = Interpreter::new(self);
+        interp.run_main(args)?;
+        Ok(interp.output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpret() {
+        let bril_text = r#"@main(n: int) {
+  one: int = const 1;
+  i: int = const 0;
+  p: ptr<int> = alloc n;
+.loop:
+  c: bool = lt i n;
+  br c .body .done;
+.body:
+  q: ptr<int> = ptradd p i;
+  sq: int = call @square i;
+  store q sq;
+  i: int = add i one;
+  jmp .loop;
+.done:
+  last: int = sub n one;
+  q: ptr<int> = ptradd p last;
+  v: int = load q;
+  free p;
+  h: float = const 0.5;
+  print v c h;
+}
+
+@square(x: int): int {
+  r: int = mul x x;
+  ret r;
+}"#;
+        let cfg = BrilCFG::from_text(bril_text).unwrap();
+        let mut interp = Interpreter::new(&cfg);
+        interp.run_main(&[Literal::Number(5)]).unwrap();
+        assert_eq!(interp.output, "16 false 0.50000000000000000\n");
+        // 6 checks of the loop condition, 5 iterations with a call each
+        assert_eq!(interp.dyn_instrs, 3 + 6 * 2 + 5 * (5 + 2) + 6);
+
+        let cfg = BrilCFG::from_text("@main { x: int = const 1; zero: int = const 0; print x; y: int = div x zero; }").unwrap();
+        let mut interp = Interpreter::new(&cfg);
+        assert!(matches!(interp.run_main(&[]), Err(Error::Runtime(_))));
+        assert_eq!(interp.output, "1\n");
+
+        let cfg = BrilCFG::from_text("@main { .l: jmp .l; }").unwrap();
+        let mut interp = Interpreter::new(&cfg).with_max_steps(10);
+        assert!(matches!(interp.run_main(&[]), Err(Error::Runtime(_))));
+    }
+}
@@ -0,0 +1,19 @@
+pub mod error;
+pub mod parser;
+pub mod cfg;
+pub mod constprop;
+pub mod dataflow;
+pub mod dce;
+pub mod dominators;
+pub mod dot;
+pub mod induction;
+pub mod interp;
+pub mod licm;
+pub mod liveness;
+pub mod loops;
+pub mod lvn;
+pub mod pass;
+pub mod reaching;
+pub mod sccp;
+pub mod ssa;
+pub mod unroll;
@@ -0,0 +1,300 @@
+// loop invariant code motion: pure instructions computing the same value on
+// every iteration move into a preheader in front of their loop
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    cfg::{BrilCFG, FunctionCFG},
+    dataflow::Site,
+    dominators::Dominators,
+    error::Result,
+    liveness::Liveness,
+    loops::LoopForest,
+    parser::{Instr, Opcode},
+    pass::{AnalysisCache, Granularity, Pass},
+    reaching::{Def, ReachingDefinitions},
+};
+
+pub struct Licm;
+
+impl Pass for Licm {
+    fn name(&self) -> &'static str {
+        "licm"
+    }
+
+    fn preserves_ssa(&self) -> bool {
+        true
+    }
+
+    fn granularity(&self) -> Granularity {
+        Granularity::Function
+    }
+
+    fn run_on_function(&mut self, func: &mut FunctionCFG, _cache: &mut AnalysisCache) -> Result<bool> {
+        func.licm()
+    }
+}
+
+impl BrilCFG {
+    pub fn licm(&mut self) -> Result<()> {
+        for func in self.functions.iter_mut() {
+            func.licm()?;
+        }
+        Ok(())
+    }
+}
+
+impl FunctionCFG {
+    // returns whether anything was hoisted
+    pub fn licm(&mut self) -> Result<bool> {
+        // inner loops first, what they hoist lands in the outer loop and may
+        // move further out from there. headers keep their names while
+        // preheaders get added
+        let forest = LoopForest::compute(self);
+        let headers = forest
+            .postorder()
+            .into_iter()
+            .map(|id| self.blocks[forest.get(id).header].name.clone())
+            .collect::<Vec<_>>();
+        let mut changed = false;
+        for header in headers {
+            changed |= self.hoist_invariants(&header)?;
+        }
+        Ok(changed)
+    }
+
+    fn hoist_invariants(&mut self, header: &str) -> Result<bool> {
+        let forest = LoopForest::compute(self);
+        let Some(header) = self.block_id(header) else {
+            return Ok(false);
+        };
+        // nothing can run before the entry
+        let Some(id) = forest.innermost(header).filter(|_| header != self.entry()) else {
+            return Ok(false);
+        };
+        let l = forest.get(id);
+        let dom = Dominators::compute(self);
+        let reaching = ReachingDefinitions::compute(self);
+        let live = Liveness::compute(self);
+
+        let mut defs_in_loop: HashMap<&str, usize> = HashMap::new();
+        for block in &l.blocks {
+            for instr in self.blocks[*block].instrs() {
+                if let Instr::Instruction { dest: Some(dest), .. } = instr {
+                    *defs_in_loop.entry(dest).or_default() += 1;
+                }
+            }
+        }
+        // blocks control leaves the loop from, returning counts as well
+        let leaving = l
+            .blocks
+            .iter()
+            .copied()
+            .filter(|block| l.exiting.contains(block) || self.blocks[*block].succs().is_empty())
+            .collect::<Vec<_>>();
+
+        // in the order they get hoisted, which respects their dependencies
+        let mut hoisted: Vec<Site> = vec![];
+        let mut invariant: HashSet<Site> = HashSet::new();
+        loop {
+            let before = hoisted.len();
+            for block in &l.blocks {
+                for (index, instr) in self.blocks[*block].instrs().iter().enumerate() {
+                    let site = Site { block: *block, index };
+                    let Instr::Instruction { op, dest: Some(dest), args, .. } = instr else {
+                        continue;
+                    };
+                    if invariant.contains(&site) || !op.is_pure() || *op == Opcode::phi {
+                        continue;
+                    }
+                    // every use in the loop has to see this definition, and
+                    // only this one
+                    if defs_in_loop[dest.as_str()] > 1 || live.block_in(header).contains(dest) {
+                        continue;
+                    }
+                    let operands_invariant = args.iter().flatten().all(|arg| {
+                        let Some(defs) = reaching.defs_of(site, arg) else {
+                            return false;
+                        };
+                        let outside = |def: &Def| !matches!(def, Def::Instr(def) if l.contains(def.block));
+                        match defs.iter().next() {
+                            Some(Def::Instr(def)) if defs.len() == 1 && invariant.contains(def) => true,
+                            _ => defs.iter().all(outside),
+                        }
+                    });
+                    if !operands_invariant {
+                        continue;
+                    }
+                    // an instruction that may not run before the loop is left
+                    // runs in the preheader anyway. that's only fine if it
+                    // can't trap and nobody after the loop sees the value
+                    let always_runs = leaving.iter().all(|leave| dom.dominates(*block, *leave));
+                    let speculable =
+                        !op.can_trap() && l.exits.iter().all(|exit| !live.block_in(*exit).contains(dest));
+                    if always_runs || speculable {
+                        invariant.insert(site);
+                        hoisted.push(site);
+                    }
+                }
+            }
+            if hoisted.len() == before {
+                break;
+            }
+        }
+        if hoisted.is_empty() {
+            return Ok(false);
+        }
+
+        let mut instrs = vec![];
+        for site in &hoisted {
+            instrs.push(self.blocks[site.block].instrs[site.index].clone());
+        }
+        for block in &l.blocks {
+            let mut index = 0;
+            self.blocks[*block].instrs.retain(|_| {
+                index += 1;
+                !invariant.contains(&Site { block: *block, index: index - 1 })
+            });
+        }
+        self.insert_preheader(l, instrs)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{interp::Interpreter, parser::Literal, ssa};
+
+    fn dyn_instrs(cfg: &BrilCFG, args: &[Literal]) -> (String, usize) {
+        let mut interp = Interpreter::new(cfg);
+        interp.run_main(args).unwrap();
+        (interp.output, interp.dyn_instrs)
+    }
+
+    #[test]
+    fn licm() {
+        let bril_text = r#"@main(a: int, b: int, n: int) {
+  i: int = const 0;
+.loop:
+  x: int = add a b;
+  two: int = const 2;
+  y: int = mul x two;
+  c: bool = lt i n;
+  br c .body .done;
+.body:
+  z: int = div a b;
+  print z;
+  i: int = add i y;
+  w: int = mul i i;
+  one: int = const 1;
+  jmp .loop;
+.done:
+  print i x;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        let args = [Literal::Number(1), Literal::Number(1), Literal::Number(10)];
+        let (output, before) = dyn_instrs(&cfg, &args);
+        cfg.licm().unwrap();
+        let bril_txt = cfg.to_text();
+        println!("bril_txt: {bril_txt}");
+        let (licm_output, after) = dyn_instrs(&cfg, &args);
+        assert_eq!(output, licm_output);
+        assert!(after < before);
+        // the entry only leads to the loop, so it serves as the preheader
+        assert!(bril_txt.contains(
+            "  i: int = const 0;\n  x: int = add a b;\n  two: int = const 2;\n  y: int = mul x two;\n  one: int = const 1;\n.loop:"
+        ));
+        // could trap, and doesn't run when leaving the loop
+        assert!(bril_txt.contains(".body:\n  z: int = div a b;"));
+        // depends on `i`, which changes every iteration
+        assert!(bril_txt.contains("  w: int = mul i i;"));
+
+        // a loop that never runs doesn't divide by zero
+        let (output, _) = dyn_instrs(&cfg, &[Literal::Number(3), Literal::Number(0), Literal::Number(0)]);
+        assert_eq!(output, "0 3\n");
+    }
+
+    #[test]
+    fn licm_keeps_loop_carried() {
+        let bril_text = r#"@main(a: int) {
+  x: int = const 0;
+  i: int = const 0;
+  three: int = const 3;
+.loop:
+  print x;
+  x: int = add a a;
+  c: bool = lt i three;
+  i: int = add i a;
+  br c .loop .done;
+.done:
+  one: int = const 1;
+  y: int = id a;
+  y: int = add y one;
+  print y;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        let before = cfg.to_text();
+        // `x` is read before the loop assigns it, the rest depends on `i`
+        assert!(!cfg.functions[0].licm().unwrap());
+        assert_eq!(cfg.to_text(), before);
+    }
+
+    #[test]
+    fn licm_nested_ssa() {
+        let bril_text = r#"@main(n: int, k: int) {
+  i: int = const 0;
+  s: int = const 0;
+  c: bool = lt n k;
+  br c .outer .other;
+.other:
+  s: int = const 100;
+.outer:
+  j: int = const 0;
+.inner:
+  t: int = mul n k;
+  u: int = add t i;
+  s: int = add s u;
+  one: int = const 1;
+  j: int = add j one;
+  d: bool = lt j n;
+  br d .inner .inner_done;
+.inner_done:
+  i: int = add i one;
+  e: bool = lt i n;
+  br e .outer .done;
+.done:
+  print s;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        let args = [Literal::Number(4), Literal::Number(3)];
+        cfg.to_ssa().unwrap();
+        // phis count as instructions too
+        let (output, before) = dyn_instrs(&cfg, &args);
+        let func = &mut cfg.functions[0];
+        assert!(func.licm().unwrap());
+        ssa::verify(func).unwrap();
+        let bril_txt = cfg.to_text();
+        println!("bril_txt: {bril_txt}");
+        let (licm_output, after) = dyn_instrs(&cfg, &args);
+        assert_eq!(output, licm_output);
+        assert!(after < before);
+
+        // `t` left both loops, `u` only the inner one. the outer header had
+        // two predecessors outside the loop, so its phis merge in the preheader
+        let func = &cfg.functions[0];
+        let forest = LoopForest::compute(func);
+        assert_eq!(forest.loops().len(), 2);
+        for instr in func.blocks.iter().flat_map(|block| block.instrs()) {
+            let Instr::Instruction { dest: Some(dest), .. } = instr else {
+                continue;
+            };
+            let block = func.blocks.iter().position(|block| block.instrs().contains(instr)).unwrap();
+            if dest.starts_with("t.") || dest.starts_with("one.") {
+                assert_eq!(forest.depth(block), 0);
+            } else if dest.starts_with("u.") {
+                assert_eq!(forest.depth(block), 1);
+            }
+        }
+        assert!(bril_txt.contains(".tmp0: int = phi"));
+    }
+}
@@ -0,0 +1,88 @@
+// live variables: a variable is live at a point if some path from there
+// reads it before it is redefined
+use std::collections::BTreeSet;
+
+use crate::{
+    cfg::FunctionCFG,
+    dataflow::{self, Analysis, DataflowResult, Direction, Site},
+    parser::Instr,
+};
+
+pub type LiveSet = BTreeSet<String>;
+
+pub struct Liveness;
+
+impl Liveness {
+    pub fn compute(func: &FunctionCFG) -> DataflowResult<LiveSet> {
+        dataflow::solve(&Liveness, func)
+    }
+}
+
+impl Analysis for Liveness {
+    type Domain = LiveSet;
+
+    const DIRECTION: Direction = Direction::Backward;
+
+    fn boundary(&self, _func: &FunctionCFG) -> LiveSet {
+        LiveSet::new()
+    }
+
+    fn top(&self, _func: &FunctionCFG) -> LiveSet {
+        LiveSet::new()
+    }
+
+    fn meet(&self, into: &mut LiveSet, other: &LiveSet) {
+        into.extend(other.iter().cloned());
+    }
+
+    fn transfer(&self, _site: Site, instr: &Instr, live: &mut LiveSet) {
+        if let Instr::Instruction { dest, args, .. } = instr {
+            if let Some(dest) = dest {
+                live.remove(dest);
+            }
+            live.extend(args.iter().flatten().cloned());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg::BrilCFG;
+
+    fn set(vars: &[&str]) -> LiveSet {
+        vars.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn live_variables() {
+        let bril_text = r#"@main(n: int) {
+  one: int = const 1;
+  i: int = const 0;
+  dead: int = const 7;
+.loop:
+  c: bool = lt i n;
+  br c .body .done;
+.body:
+  i: int = add i one;
+  jmp .loop;
+.done:
+  print i;
+}"#;
+        let cfg = BrilCFG::from_text(bril_text).unwrap();
+        let main = cfg.function("main").unwrap();
+        let id = |name| main.block_id(name).unwrap();
+        let live = Liveness::compute(main);
+        assert_eq!(live.block_in(main.entry()), &set(&["n"]));
+        assert_eq!(live.block_out(main.entry()), &set(&["i", "n", "one"]));
+        assert_eq!(live.block_in(id("loop")), &set(&["i", "n", "one"]));
+        assert_eq!(live.block_in(id("done")), &set(&["i"]));
+        assert_eq!(live.block_out(id("done")), &set(&[]));
+
+        let facts = live.instr_facts(&Liveness, main, main.entry());
+        // `dead` is never live, `i` only after its definition
+        assert_eq!(facts[1], set(&["n", "one"]));
+        assert_eq!(facts[2], set(&["i", "n", "one"]));
+        assert!(facts.iter().all(|fact| !fact.contains("dead")));
+    }
+}
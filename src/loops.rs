@@ -0,0 +1,554 @@
+// natural loops and the loop nesting forest, built from the back edges
+// found with the dominator tree
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt::{self, Display},
+};
+
+use crate::{
+    cfg::{BlockId, FunctionCFG},
+    dominators::Dominators,
+    error::Result,
+    parser::{Instr, Opcode},
+    ssa::{fresh_var, var_names, UNDEFINED},
+};
+
+pub type LoopId = usize;
+
+pub struct Loop {
+    pub header: BlockId,
+    // sorted, including the header
+    pub blocks: Vec<BlockId>,
+    // sources of the back edges to the header
+    pub latches: Vec<BlockId>,
+    // blocks in the loop with a successor outside of it
+    pub exiting: Vec<BlockId>,
+    // blocks outside of the loop with a predecessor in it
+    pub exits: Vec<BlockId>,
+    pub parent: Option<LoopId>,
+    pub children: Vec<LoopId>,
+    // 1 for outermost loops
+    pub depth: usize,
+}
+
+impl Loop {
+    pub fn contains(&self, block: BlockId) -> bool {
+        self.blocks.binary_search(&block).is_ok()
+    }
+}
+
+// a cycle entered through more than one block, so no block dominates the
+// rest and it has no natural loop
+#[derive(Debug, PartialEq, Eq)]
+pub struct IrreducibleRegion {
+    pub blocks: Vec<BlockId>,
+    pub entries: Vec<BlockId>,
+}
+
+pub struct LoopForest {
+    loops: Vec<Loop>,
+    roots: Vec<LoopId>,
+    // innermost loop of every block
+    innermost: Vec<Option<LoopId>>,
+    irreducible: Vec<IrreducibleRegion>,
+}
+
+// blocks reachable from `start` following `next`, `start` included
+fn reach<'a, F>(num_blocks: usize, start: BlockId, next: F) -> Vec<bool>
+where
+    F: Fn(BlockId) -> &'a [BlockId],
+{
+    let mut seen = vec![false; num_blocks];
+    seen[start] = true;
+    let mut stack = vec![start];
+    while let Some(block) = stack.pop() {
+        for other in next(block) {
+            if !seen[*other] {
+                seen[*other] = true;
+                stack.push(*other);
+            }
+        }
+    }
+    seen
+}
+
+impl LoopForest {
+    pub fn compute(func: &FunctionCFG) -> Self {
+        let n = func.blocks.len();
+        let dom = Dominators::compute(func);
+        let rpo = func.reverse_postorder();
+        let mut position = vec![usize::MAX; n];
+        for (i, block) in rpo.iter().enumerate() {
+            position[*block] = i;
+        }
+
+        // a retreating edge in the dfs goes to a block no later in reverse
+        // postorder. it's a back edge if its target dominates its source,
+        // otherwise it closes an irreducible cycle
+        let mut latches: Vec<Vec<BlockId>> = vec![vec![]; n];
+        let mut irreducible = vec![];
+        for (from, to) in func.edges().filter(|(from, _)| dom.is_reachable(*from)) {
+            if position[to] > position[from] {
+                continue;
+            }
+            if dom.dominates(to, from) {
+                latches[to].push(from);
+                continue;
+            }
+            // the cycle through the edge: reachable from `to` and reaching `from`
+            let forward = reach(n, to, |block| func.blocks[block].succs());
+            let backward = reach(n, from, |block| func.blocks[block].preds());
+            let blocks = (0..n)
+                .filter(|block| forward[*block] && backward[*block] && dom.is_reachable(*block))
+                .collect::<Vec<_>>();
+            if irreducible
+                .iter()
+                .any(|region: &IrreducibleRegion| region.blocks == blocks)
+            {
+                continue;
+            }
+            let entries = blocks
+                .iter()
+                .copied()
+                .filter(|block| {
+                    *block == func.entry()
+                        || func.blocks[*block].preds().iter().any(|pred| {
+                            dom.is_reachable(*pred) && blocks.binary_search(pred).is_err()
+                        })
+                })
+                .collect();
+            irreducible.push(IrreducibleRegion { blocks, entries });
+        }
+
+        let mut loops = vec![];
+        for header in rpo
+            .iter()
+            .copied()
+            .filter(|header| !latches[*header].is_empty())
+        {
+            // walk backwards from the latches without leaving through the header
+            let mut body = BTreeSet::from([header]);
+            let mut stack = latches[header].clone();
+            while let Some(block) = stack.pop() {
+                if body.insert(block) {
+                    stack.extend(
+                        func.blocks[block]
+                            .preds()
+                            .iter()
+                            .filter(|pred| dom.is_reachable(**pred)),
+                    );
+                }
+            }
+            let blocks = body.into_iter().collect::<Vec<_>>();
+            let mut exiting = vec![];
+            let mut exits = BTreeSet::new();
+            for block in &blocks {
+                let outside = func.blocks[*block]
+                    .succs()
+                    .iter()
+                    .filter(|succ| blocks.binary_search(succ).is_err())
+                    .collect::<Vec<_>>();
+                if !outside.is_empty() {
+                    exiting.push(*block);
+                    exits.extend(outside);
+                }
+            }
+            loops.push(Loop {
+                header,
+                blocks,
+                latches: latches[header].clone(),
+                exiting,
+                exits: exits.into_iter().collect(),
+                parent: None,
+                children: vec![],
+                depth: 0,
+            });
+        }
+
+        // natural loops with different headers are disjoint or nested, so
+        // the parent of a loop is the smallest larger loop holding its header
+        let mut by_size = (0..loops.len()).collect::<Vec<_>>();
+        by_size.sort_by_key(|id| loops[*id].blocks.len());
+        let mut innermost = vec![None; n];
+        for id in &by_size {
+            for block in &loops[*id].blocks {
+                innermost[*block].get_or_insert(*id);
+            }
+        }
+        let mut roots = vec![];
+        for id in 0..loops.len() {
+            let header = loops[id].header;
+            let size = loops[id].blocks.len();
+            let parent = by_size
+                .iter()
+                .copied()
+                .find(|other| loops[*other].blocks.len() > size && loops[*other].contains(header));
+            loops[id].parent = parent;
+            match parent {
+                Some(parent) => loops[parent].children.push(id),
+                None => roots.push(id),
+            }
+        }
+        // headers come in reverse postorder, so parents before children
+        for id in 0..loops.len() {
+            loops[id].depth = loops[id].parent.map_or(1, |parent| loops[parent].depth + 1);
+        }
+
+        Self {
+            loops,
+            roots,
+            innermost,
+            irreducible,
+        }
+    }
+
+    pub fn loops(&self) -> &[Loop] {
+        &self.loops
+    }
+
+    pub fn get(&self, id: LoopId) -> &Loop {
+        &self.loops[id]
+    }
+
+    pub fn roots(&self) -> &[LoopId] {
+        &self.roots
+    }
+
+    pub fn innermost(&self, block: BlockId) -> Option<LoopId> {
+        self.innermost[block]
+    }
+
+    pub fn depth(&self, block: BlockId) -> usize {
+        self.innermost[block].map_or(0, |id| self.loops[id].depth)
+    }
+
+    // inner loops before the loops containing them
+    pub fn postorder(&self) -> Vec<LoopId> {
+        let mut order = vec![];
+        let mut stack = self
+            .roots
+            .iter()
+            .rev()
+            .map(|id| (*id, false))
+            .collect::<Vec<_>>();
+        while let Some((id, done)) = stack.pop() {
+            if done {
+                order.push(id);
+                continue;
+            }
+            stack.push((id, true));
+            stack.extend(
+                self.loops[id]
+                    .children
+                    .iter()
+                    .rev()
+                    .map(|child| (*child, false)),
+            );
+        }
+        order
+    }
+
+    pub fn irreducible(&self) -> &[IrreducibleRegion] {
+        &self.irreducible
+    }
+
+    pub fn is_reducible(&self) -> bool {
+        self.irreducible.is_empty()
+    }
+
+    // a printable summary of the forest, with block names from `func`
+    pub fn display<'a>(&'a self, func: &'a FunctionCFG) -> impl Display + 'a {
+        ForestDisplay { forest: self, func }
+    }
+}
+
+struct ForestDisplay<'a> {
+    forest: &'a LoopForest,
+    func: &'a FunctionCFG,
+}
+
+impl Display for ForestDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = |blocks: &[BlockId]| {
+            blocks
+                .iter()
+                .map(|block| format!(".{}", self.func.blocks[*block].name()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut stack = self.forest.roots.iter().rev().copied().collect::<Vec<_>>();
+        while let Some(id) = stack.pop() {
+            let l = &self.forest.loops[id];
+            writeln!(
+                f,
+                "{}loop .{}: blocks [{}] latches [{}] exits [{}]",
+                "  ".repeat(l.depth - 1),
+                self.func.blocks[l.header].name(),
+                names(&l.blocks),
+                names(&l.latches),
+                names(&l.exits)
+            )?;
+            stack.extend(l.children.iter().rev());
+        }
+        for region in &self.forest.irreducible {
+            writeln!(
+                f,
+                "irreducible: blocks [{}] entries [{}]",
+                names(&region.blocks),
+                names(&region.entries)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FunctionCFG {
+    // put `instrs` in a block that runs right before the loop is entered and
+    // returns its id. a predecessor that only leads to the header is reused,
+    // otherwise a new block takes over the edges from outside of the loop.
+    // the phis of the header get a single input from the preheader, with
+    // several outside predecessors the preheader merges them first
+    pub fn insert_preheader(&mut self, l: &Loop, mut instrs: Vec<Instr>) -> Result<BlockId> {
+        let header = l.header;
+        let outside_preds = self.blocks[header]
+            .preds()
+            .iter()
+            .copied()
+            .filter(|pred| !l.contains(*pred))
+            .collect::<Vec<_>>();
+        if let [pred] = outside_preds[..] {
+            if self.blocks[pred].succs() == [header] {
+                let block = &mut self.blocks[pred];
+                let at = match block.instrs.last() {
+                    Some(Instr::Instruction { op: Opcode::jmp | Opcode::br, .. }) => block.instrs.len() - 1,
+                    _ => block.instrs.len(),
+                };
+                block.instrs.splice(at..at, instrs);
+                return Ok(pred);
+            }
+        }
+
+        let outside_labels = outside_preds
+            .iter()
+            .map(|pred| self.blocks[*pred].name.clone())
+            .collect::<HashSet<_>>();
+        let pre = self.add_block("preheader", vec![]);
+        let preheader = self.blocks[pre].name.clone();
+        let mut names = var_names(self);
+        let mut phis = vec![];
+        for instr in self.blocks[header].instrs.iter_mut() {
+            let Instr::Instruction { op: Opcode::phi, dest: Some(dest), typ, args: Some(args), labels: Some(labels), .. } =
+                instr
+            else {
+                continue;
+            };
+            let (mut outside_args, mut outside) = (vec![], vec![]);
+            let (mut inside_args, mut inside) = (vec![], vec![]);
+            for (arg, label) in args.drain(..).zip(labels.drain(..)) {
+                if outside_labels.contains(&label) {
+                    outside_args.push(arg);
+                    outside.push(label);
+                } else {
+                    inside_args.push(arg);
+                    inside.push(label);
+                }
+            }
+            let arg = if outside_preds.len() == 1 {
+                outside_args.pop().unwrap_or_else(|| UNDEFINED.to_string())
+            } else {
+                let merged = fresh_var(&mut names, dest);
+                phis.push(Instr::Instruction {
+                    op: Opcode::phi,
+                    dest: Some(merged.clone()),
+                    typ: typ.clone(),
+                    args: Some(outside_args),
+                    funcs: None,
+                    labels: Some(outside),
+                    value: None,
+                });
+                merged
+            };
+            inside_args.push(arg);
+            inside.push(preheader.clone());
+            *args = inside_args;
+            *labels = inside;
+        }
+        phis.append(&mut instrs);
+        phis.push(Instr::Instruction {
+            op: Opcode::jmp,
+            dest: None,
+            typ: None,
+            args: None,
+            funcs: None,
+            labels: Some(vec![self.blocks[header].name.clone()]),
+            value: None,
+        });
+        self.blocks[pre].instrs = phis;
+        for pred in outside_preds {
+            self.redirect_edge(pred, header, pre);
+        }
+        self.resolve_cfg()?;
+        Ok(pre)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cfg::BrilCFG,
+        dominators::tests::{random_function, Rng},
+        ssa,
+    };
+
+    #[test]
+    fn loop_forest() {
+        let bril_text = r#"@main(c: bool) {
+.outer:
+  br c .inner .done;
+.inner:
+  br c .inner_body .outer_latch;
+.inner_body:
+  br c .inner .break;
+.outer_latch:
+  jmp .outer;
+.break:
+  jmp .done;
+.done:
+  print c;
+.second:
+  br c .second .end;
+.end:
+  ret;
+}"#;
+        let cfg = BrilCFG::from_text(bril_text).unwrap();
+        let main = cfg.function("main").unwrap();
+        let id = |name| main.block_id(name).unwrap();
+        let forest = LoopForest::compute(main);
+        println!("{}", forest.display(main));
+        assert!(forest.is_reducible());
+        assert_eq!(forest.loops().len(), 3);
+        assert_eq!(forest.roots().len(), 2);
+
+        let outer = forest.innermost(id("outer")).unwrap();
+        let inner = forest.innermost(id("inner")).unwrap();
+        let second = forest.innermost(id("second")).unwrap();
+        assert_eq!(
+            forest.get(outer).blocks,
+            vec![
+                id("outer"),
+                id("inner"),
+                id("inner_body"),
+                id("outer_latch")
+            ]
+        );
+        assert_eq!(forest.get(outer).latches, vec![id("outer_latch")]);
+        assert_eq!(forest.get(outer).exits, vec![id("break"), id("done")]);
+        assert_eq!(
+            forest.get(outer).exiting,
+            vec![id("outer"), id("inner_body")]
+        );
+        assert_eq!(
+            forest.get(inner).blocks,
+            vec![id("inner"), id("inner_body")]
+        );
+        assert_eq!(forest.get(inner).parent, Some(outer));
+        assert_eq!(forest.get(inner).depth, 2);
+        assert_eq!(forest.get(outer).children, vec![inner]);
+        assert_eq!(forest.get(second).blocks, vec![id("second")]);
+        assert_eq!(forest.depth(id("inner_body")), 2);
+        assert_eq!(forest.depth(id("break")), 0);
+        assert_eq!(forest.postorder(), vec![inner, outer, second]);
+        assert!(forest.display(main).to_string().contains("  loop .inner: blocks [.inner, .inner_body] latches [.inner_body] exits [.outer_latch, .break]"));
+    }
+
+    #[test]
+    fn irreducible() {
+        // the cycle between a and b can be entered at either block
+        let bril_text = r#"@main(c: bool) {
+  br c .a .b;
+.a:
+  br c .b .done;
+.b:
+  br c .a .done;
+.done:
+  ret;
+}"#;
+        let cfg = BrilCFG::from_text(bril_text).unwrap();
+        let main = cfg.function("main").unwrap();
+        let id = |name| main.block_id(name).unwrap();
+        let forest = LoopForest::compute(main);
+        assert!(forest.loops().is_empty());
+        assert_eq!(
+            forest.irreducible(),
+            &[IrreducibleRegion {
+                blocks: vec![id("a"), id("b")],
+                entries: vec![id("a"), id("b")],
+            }]
+        );
+        assert_eq!(
+            forest.display(main).to_string(),
+            "irreducible: blocks [.a, .b] entries [.a, .b]\n"
+        );
+    }
+
+    #[test]
+    fn preheader_phi_without_outside_input() {
+        // the entry also branches around the loop, so it can't be the preheader
+        let bril_text = r#"@main(c: bool) {
+.entry:
+  br c .loop .done;
+.loop:
+  x: int = phi x.1 .loop;
+  one: int = const 1;
+  x.1: int = add x one;
+  br c .loop .done;
+.done:
+  ret;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        let func = &mut cfg.functions[0];
+        let forest = LoopForest::compute(func);
+        func.insert_preheader(forest.get(0), vec![]).unwrap();
+        let bril_txt = cfg.to_text();
+        println!("bril_txt: {bril_txt}");
+        assert!(bril_txt.contains("  x: int = phi x.1 __undefined .loop .preheader0;"));
+        ssa::verify(&cfg.functions[0]).unwrap();
+    }
+
+    #[test]
+    fn loop_forest_random() {
+        let mut rng = Rng::new(5);
+        for _ in 0..200 {
+            let n = rng.below(10) + 1;
+            let cfg = BrilCFG::from_text(&random_function(&mut rng, n)).unwrap();
+            let func = &cfg.functions[0];
+            let dom = Dominators::compute(func);
+            let forest = LoopForest::compute(func);
+            for (id, l) in forest.loops().iter().enumerate() {
+                for block in &l.blocks {
+                    assert!(dom.dominates(l.header, *block));
+                    // the innermost loop of a block is the smallest one holding it
+                    let innermost = forest.get(forest.innermost(*block).unwrap());
+                    assert!(innermost.blocks.len() <= l.blocks.len());
+                }
+                for latch in &l.latches {
+                    assert!(func.blocks[*latch].succs().contains(&l.header));
+                }
+                if let Some(parent) = l.parent {
+                    let parent = forest.get(parent);
+                    assert!(l.blocks.iter().all(|block| parent.contains(*block)));
+                    assert_eq!(l.depth, parent.depth + 1);
+                }
+                assert_eq!(
+                    forest
+                        .postorder()
+                        .iter()
+                        .filter(|other| **other == id)
+                        .count(),
+                    1
+                );
+            }
+        }
+    }
+}
@@ -2,12 +2,15 @@ use std::collections::HashMap;
 
 use crate::{
     cfg::{Block, BrilCFG},
+    error::{Error, Result},
     parser::{Instr, Literal, Opcode},
+    pass::Pass,
 };
 
 type VarName = String;
 type VarNum = usize;
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct LVN {
     table: HashMap<LVNTuple, (VarNum, VarName)>,
     var2num: HashMap<VarName, VarNum>,
@@ -19,6 +22,9 @@ pub struct LVN {
 pub struct LVNTuple {
     op: LVNOpcode,
     args: Vec<VarNum>,
+    // impure values (loads, calls, ...) can never be shared, so each one
+    // is tagged with its own value number to keep the tuple unique
+    instance: Option<VarNum>,
 }
 
 #[allow(non_camel_case_types)]
@@ -43,30 +49,52 @@ pub enum LVNOpcode {
     id,
     print,
     nop,
-    cst(Literal)
+    cst(Literal),
+    // a value flowing into the block: a function argument or a variable
+    // defined in another block
+    input,
+    alloc,
+    free,
+    store,
+    load,
+    ptradd,
+    fadd,
+    fmul,
+    fsub,
+    fdiv,
+    feq,
+    flt,
+    fgt,
+    fle,
+    fge,
+    phi,
 }
 
 impl LVNOpcode {
-    pub fn from_instr(instr: &Instr) -> Self {
+    pub fn from_instr(instr: &Instr) -> Result<Self> {
         if let Instr::Instruction { op, value, ..}  = instr {
             let mut vals = vec![];
-            match op {
-                Opcode::cst => {
-                    if let Some(value) = value {
-                        vals.push(value.clone());
-                    } else {
-                        panic!("const without value");
-                    }
+            if op == &Opcode::cst {
+                if let Some(value) = value {
+                    vals.push(value.clone());
+                } else {
+                    return Err(Error::MalformedCfg(format!("const without value: {instr}")));
                 }
-                _ => {}
             }
-            Self::from_opcode(op.clone(), &vals)
+            Ok(Self::from_opcode(op.clone(), &vals))
         } else {
-            panic!("unexpected label {instr:?}");
+            Err(Error::MalformedCfg(format!("unexpected label {instr}")))
         }
     }
 
-    fn from_opcode(op: Opcode, val: &Vec<Literal>) -> Self {
+    // operands of these can be reordered without changing the result.
+    // float ops are left out on purpose: with two NaN operands the one
+    // that propagates depends on the operand order
+    fn is_commutative(&self) -> bool {
+        matches!(self, LVNOpcode::add | LVNOpcode::mul | LVNOpcode::eq | LVNOpcode::and | LVNOpcode::or)
+    }
+
+    fn from_opcode(op: Opcode, val: &[Literal]) -> Self {
         match op {
             Opcode::add => LVNOpcode::add,
             Opcode::mul => LVNOpcode::mul,
@@ -91,72 +119,113 @@ impl LVNOpcode {
                 assert!(val.len() == 1);
                 LVNOpcode::cst(val[0].clone())
             },
+            Opcode::alloc => LVNOpcode::alloc,
+            Opcode::free => LVNOpcode::free,
+            Opcode::store => LVNOpcode::store,
+            Opcode::load => LVNOpcode::load,
+            Opcode::ptradd => LVNOpcode::ptradd,
+            Opcode::fadd => LVNOpcode::fadd,
+            Opcode::fmul => LVNOpcode::fmul,
+            Opcode::fsub => LVNOpcode::fsub,
+            Opcode::fdiv => LVNOpcode::fdiv,
+            Opcode::feq => LVNOpcode::feq,
+            Opcode::flt => LVNOpcode::flt,
+            Opcode::fgt => LVNOpcode::fgt,
+            Opcode::fle => LVNOpcode::fle,
+            Opcode::fge => LVNOpcode::fge,
+            Opcode::phi => LVNOpcode::phi,
         }
     }
 }
 
+pub struct Lvn;
+
+impl Pass for Lvn {
+    fn name(&self) -> &'static str {
+        "lvn"
+    }
+
+    fn preserves_ssa(&self) -> bool {
+        true
+    }
+
+    fn run_on_block(&mut self, block: &mut Block) -> Result<bool> {
+        let before = block.instrs.clone();
+        block.lvn()?;
+        Ok(block.instrs != before)
+    }
+}
+
 impl BrilCFG {
-    pub fn lvn(&mut self) {
-        for block in self.blocks.iter_mut() {
-            block.lvn();
+    pub fn lvn(&mut self) -> Result<()> {
+        for block in self.blocks_mut() {
+            block.lvn()?;
         }
+        Ok(())
     }
 }
 
 impl Block {
-    pub fn lvn(&mut self) {
-        assert!(self.lvn.is_none(), "calling lvn multiple times");
+    pub fn lvn(&mut self) -> Result<()> {
         let mut lvn = LVN::new();
-        let mut rewrite = HashMap::new();
+        let mut new_instrs = Vec::with_capacity(self.instrs.len());
         for instr in &self.instrs {
-            let tuple = lvn.tuple_from_instr(instr);
-            if lvn.table.contains_key(&tuple) {
-                let (num, var) = &lvn.table[&tuple];
-                if let Instr::Instruction { dest, typ, .. } = instr {
-                    if let Some(dest) = dest {
-                        let typ = typ.as_ref().expect("instr {instr} does't have type");
-                        // replace instr with copy of var
-                        let new_instr = Instr::new_id_instr(dest, var, typ.clone());
-                        rewrite.insert(instr.clone(), new_instr);
-                        lvn.var2num.insert(dest.clone(), *num);
-                    }
+            let mut tuple = lvn.tuple_from_instr(instr)?;
+            // the value of a phi depends on its labels as well, so it is
+            // never shared
+            let pure = matches!(instr, Instr::Instruction { op, .. } if op.is_pure() && *op != Opcode::phi);
+            if pure && lvn.table.contains_key(&tuple) {
+                let (num, var) = lvn.table[&tuple].clone();
+                if let Instr::Instruction { dest: Some(dest), typ, .. } = instr {
+                    let typ = typ
+                        .as_ref()
+                        .ok_or_else(|| Error::MalformedCfg(format!("instr {instr} doesn't have type")))?;
+                    // replace instr with copy of var
+                    let new_instr = Instr::new_id_instr(dest, &var, typ.clone());
+                    new_instrs.push(new_instr);
+                    lvn.clobber(dest);
+                    // `x = id x` keeps the value in `x`
+                    lvn.table.entry(tuple).or_insert((num, dest.clone()));
+                    lvn.var2num.insert(dest.clone(), num);
+                } else {
+                    new_instrs.push(instr.clone());
                 }
             } else {
-                if let Instr::Instruction { op, dest, args,  .. } = instr {
+                if let Instr::Instruction { op, dest, .. } = instr {
+                    // the arguments are read before the dest changes
+                    let new_instr = lvn.rewrite_instr_args(instr)?;
                     if let Some(dest) = dest {
+                        lvn.clobber(dest);
                         let num = lvn.next_var_num();
-                        // FIXME: what if the dest is duplicated afterwards
+                        if !pure {
+                            tuple.instance = Some(num);
+                        }
                         if op == &Opcode::id {
-                            let args = args.as_ref().unwrap();
-                            lvn.table.insert(tuple.clone(), (num, args[0].clone()));
+                            let src = match &new_instr {
+                                Instr::Instruction { args: Some(args), .. } => args.first(),
+                                _ => None,
+                            };
+                            let src = src.ok_or_else(|| Error::MalformedCfg(format!("id without argument: {instr}")))?;
+                            lvn.table.insert(tuple.clone(), (num, src.clone()));
                         } else {
                             lvn.table.insert(tuple.clone(), (num, dest.clone()));
                         }
                         lvn.num2tuple.insert(num, tuple);
                         lvn.var2num.insert(dest.clone(), num);
-                    } else {
-                        // don't do anything
                     }
 
-                    let new_instr = lvn.rewrite_instr_args(instr);
-                    rewrite.insert(instr.clone(), new_instr);
+                    new_instrs.push(new_instr);
                 }
             }
         }
+        self.instrs = new_instrs;
+        Ok(())
+    }
+}
 
-        let new_instr = self
-            .instrs
-            .iter()
-            .map(|x| {
-                if let Some(instr) = rewrite.get(x) {
-                    instr
-                } else {
-                    x
-                }
-            })
-            .map(|x| x.clone())
-            .collect::<Vec<_>>();
-        self.instrs = new_instr;
+impl Default for LVN {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -169,82 +238,118 @@ impl LVN {
             cur_num: 0,
         }
     }
-    fn debug(&self) {
-        println!("{:?}", self.table);
-        println!("{:?}", self.var2num);
-        println!("{:?}", self.num2tuple);
-    }
     pub fn next_var_num(&mut self) -> VarNum {
         let ret = self.cur_num;
         self.cur_num += 1;
         ret
     }
-    pub fn rewrite_instr_args(&self, instr: &Instr) -> Instr {
+    pub fn rewrite_instr_args(&self, instr: &Instr) -> Result<Instr> {
         let mut new_args = vec![];
-        if let Instr::Instruction { args, .. } = instr {
-            if let Some(args) = args {
-                for arg in args {
-                    new_args.push(self.replace_var(arg));
-                }
+        if let Instr::Instruction { args: Some(args), .. } = instr {
+            for arg in args {
+                new_args.push(self.replace_var(arg)?);
             }
         }
 
         let mut ret = instr.clone();
-        if let Instr::Instruction { args, .. } = &mut ret {
-            args.replace(new_args);
+        if let Instr::Instruction { args: Some(args), .. } = &mut ret {
+            *args = new_args;
         }
 
-        ret
+        Ok(ret)
     }
-    fn replace_var(&self, var: &str) -> String {
-        if let Some(num) = self.var2num.get(var) {
-            if let Some(index) = self.num2tuple.get(num) {
-                if let Some((_, var)) = self.table.get(index) {
-                    var.clone()
-                } else {
-                    panic!("cannot find lvn entry of num {num}, index: {index:?}");
+    // `var` is about to be assigned. the values it holds until then move to
+    // another variable holding them too, or can't be reused anymore
+    fn clobber(&mut self, var: &str) {
+        let held = self
+            .table
+            .iter()
+            .filter(|(_, (_, holder))| holder == var)
+            .map(|(tuple, (num, _))| (tuple.clone(), *num))
+            .collect::<Vec<_>>();
+        for (tuple, num) in held {
+            let other = self
+                .var2num
+                .iter()
+                .filter(|(other, other_num)| **other_num == num && other.as_str() != var)
+                .map(|(other, _)| other.clone())
+                .min();
+            match other {
+                Some(other) => {
+                    self.table.insert(tuple, (num, other));
+                }
+                None => {
+                    self.table.remove(&tuple);
                 }
-            } else {
-                panic!("cannot find lvn entry of num {num}");
             }
+        }
+    }
+    fn replace_var(&self, var: &str) -> Result<String> {
+        if let Some(num) = self.var2num.get(var) {
+            self.num2tuple
+                .get(num)
+                .and_then(|index| self.table.get(index))
+                .map(|(_, var)| var.clone())
+                .ok_or_else(|| Error::UnknownVariable(var.to_string()))
         } else {
-            var.to_string()
+            Ok(var.to_string())
         }
     }
-    pub fn tuple_from_instr(&self, instr: &Instr) -> LVNTuple {
-        if let Instr::Instruction { op, args, .. } = instr {
+    pub fn tuple_from_instr(&mut self, instr: &Instr) -> Result<LVNTuple> {
+        if let Instr::Instruction { args, .. } = instr {
             let mut args = if let Some(args) = args {
                 args.iter()
                     .map(|arg| {
                         self.resolve_arg(arg)
                     })
-                    .collect()
+                    .collect::<Result<Vec<_>>>()?
             } else {
                 vec![]
             };
-            args.sort();
-            LVNTuple {
-                op: LVNOpcode::from_instr(instr),
-                args,
+            let op = LVNOpcode::from_instr(instr)?;
+            if op.is_commutative() {
+                args.sort();
             }
+            Ok(LVNTuple {
+                op,
+                args,
+                instance: None,
+            })
         } else {
-            panic!("try to convert from label {instr:?} to LVNTuple");
+            Err(Error::MalformedCfg(format!("try to convert from label {instr} to LVNTuple")))
         }
     }
 
-    fn resolve_arg(&self, arg: &str) -> VarNum {
-        let num = self.var2num.get(arg).expect("cannot find variable {arg} in lvn");
-        let tuple = &self.num2tuple[num];
+    fn resolve_arg(&mut self, arg: &str) -> Result<VarNum> {
+        let Some(num) = self.var2num.get(arg) else {
+            // first time we see it, it was defined outside of this block
+            let num = self.next_var_num();
+            let tuple = LVNTuple {
+                op: LVNOpcode::input,
+                args: vec![],
+                instance: Some(num),
+            };
+            self.table.insert(tuple.clone(), (num, arg.to_string()));
+            self.num2tuple.insert(num, tuple);
+            self.var2num.insert(arg.to_string(), num);
+            return Ok(num);
+        };
+        let tuple = self
+            .num2tuple
+            .get(num)
+            .ok_or_else(|| Error::UnknownVariable(arg.to_string()))?;
         if tuple.op == LVNOpcode::id {
-            return tuple.args[0]
+            return Ok(tuple.args[0])
         }
-        *num
+        Ok(*num)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::cfg::BrilCFG;
+    use crate::parser::Literal;
+    use crate::pass::PassManager;
 
     #[test]
     fn lvn() {
@@ -256,9 +361,9 @@ mod tests {
         prod: int = mul sum1 sum2;
         print prod;
 }"#;
-        let mut cfg = BrilCFG::from_text(bril_text);
-        cfg.lvn();
-        cfg.trivial_dce();
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        cfg.lvn().unwrap();
+        cfg.trivial_dce().unwrap();
         let bril_txt = cfg.to_text();
         println!("out: {bril_txt}");
         assert!(!bril_txt.contains("sum2: int"));
@@ -273,9 +378,9 @@ mod tests {
         copy3: int = id copy2;
         print copy3;
 }"#;
-        let mut cfg = BrilCFG::from_text(bril_text);
-        cfg.lvn();
-        cfg.trivial_dce();
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        cfg.lvn().unwrap();
+        cfg.trivial_dce().unwrap();
         let bril_txt = cfg.to_text();
         println!("out: {bril_txt}");
         assert!(!bril_txt.contains("copy1"));
@@ -293,11 +398,99 @@ mod tests {
         prod: int = mul sum1 sum2;
         print prod;
 }"#;
-        let mut cfg = BrilCFG::from_text(bril_text);
-        cfg.lvn();
-        cfg.trivial_dce();
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        cfg.lvn().unwrap();
+        cfg.trivial_dce().unwrap();
         let bril_txt = cfg.to_text();
         println!("out: {bril_txt}");
         assert!(!bril_txt.contains("sum2"));
     }
+
+    #[test]
+    fn loads_are_not_shared() {
+        let bril_text = r#"@main{
+        one: int = const 1;
+        p: ptr<int> = alloc one;
+        store p one;
+        x: int = load p;
+        two: int = const 2;
+        store p two;
+        y: int = load p;
+        q: ptr<int> = ptradd p one;
+        r: ptr<int> = ptradd p one;
+        print x y;
+        free p;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        cfg.lvn().unwrap();
+        let bril_txt = cfg.to_text();
+        println!("out: {bril_txt}");
+        assert!(bril_txt.contains("y: int = load p;"));
+        assert!(bril_txt.contains("r: ptr<int> = id q;"));
+        assert!(bril_txt.contains("print x y;"));
+    }
+
+    #[test]
+    fn float_ops() {
+        let bril_text = r#"@main{
+        a: float = const 0.0;
+        b: float = const -0.0;
+        lt1: bool = flt a b;
+        lt2: bool = flt b a;
+        sum1: float = fadd a b;
+        sum2: float = fadd a b;
+        print lt1 lt2 sum1 sum2;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        cfg.lvn().unwrap();
+        let bril_txt = cfg.to_text();
+        println!("out: {bril_txt}");
+        assert!(bril_txt.contains("b: float = const -0.0;"));
+        assert!(bril_txt.contains("lt2: bool = flt b a;"));
+        assert!(bril_txt.contains("sum2: float = id sum1;"));
+    }
+
+    #[test]
+    fn values_from_outside_the_block() {
+        let bril_text = r#"@main(a: int) {
+        b: int = const 1;
+        jmp .next;
+.next:
+        sum1: int = add a b;
+        sum2: int = add b a;
+        print sum1 sum2;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        cfg.lvn().unwrap();
+        let bril_txt = cfg.to_text();
+        println!("out: {bril_txt}");
+        assert!(bril_txt.contains("sum2: int = id sum1;"));
+    }
+
+    #[test]
+    fn redefined_dest() {
+        let bril_text = r#"@main(a: int, b: int) {
+        x: int = add a b;
+        x: int = const 3;
+        y: int = add a b;
+        print y;
+        c: int = id a;
+        a: int = const 5;
+        print c;
+        s1: int = add c b;
+        s2: int = add c b;
+        s1: int = const 7;
+        s3: int = add c b;
+        print s1 s2 s3;
+}"#;
+        let args = [Literal::Number(1), Literal::Number(2)];
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        let expected = cfg.interpret(&args).unwrap();
+        PassManager::from_pipeline("lvn").unwrap().run(&mut cfg).unwrap();
+        println!("out: {}", cfg.to_text());
+        assert_eq!(cfg.interpret(&args).unwrap(), expected);
+        let bril_txt = cfg.to_text();
+        assert!(bril_txt.contains("  print c;"));
+        assert!(bril_txt.contains("s3: int = id y;"));
+    }
 }
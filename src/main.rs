@@ -1,28 +1,189 @@
-use std::io::stdin;
+use std::{
+    env, fs,
+    io::{stdin, Read},
+    process::exit,
+};
 
-use parser::Bril;
-use cfg::BrilCFG;
+use cfg::cfg::BrilCFG;
+use cfg::error::Result;
+use cfg::loops::LoopForest;
+use cfg::pass::{registry, PassManager};
 
-mod parser;
-mod cfg;
-mod dce;
-mod utils;
-mod lvn;
+const USAGE: &str = "usage: cfg [-p PASSES] [--emit=json|text|dot] [--dump-cfg] [--dump-loops] [--list-passes] [FILE]
 
-// TODO: use input flag to dispatch optimization function on bril
+reads a bril program (json or text) from FILE or stdin, runs the
+comma separated PASSES over it and writes the result to stdout
 
-fn main() {
-    let mut s = String::new();
-    for line in stdin().lines() {
-        if let Ok(res) = line {
-            s.push_str(&res);
+options:
+  -p, --passes PASSES  passes to run in order, e.g. `lvn,dce*`, where a
+                       trailing `*` reruns a pass until nothing changes
+  --emit FORMAT        output format, `json` (default), `text` or `dot`
+  --dump-cfg           print the basic blocks instead of the program
+  --dump-loops         print the loop nesting forest of every function and
+                       any irreducible regions instead of the program
+  --list-passes        print the available passes and exit
+  -h, --help           print this message and exit";
+
+#[derive(Debug, PartialEq)]
+enum Emit {
+    Json,
+    Text,
+    Dot,
+}
+
+struct Options {
+    passes: PassManager,
+    emit: Emit,
+    dump_cfg: bool,
+    dump_loops: bool,
+    input: Option<String>,
+}
+
+fn parse_emit(format: &str) -> std::result::Result<Emit, String> {
+    match format {
+        "json" => Ok(Emit::Json),
+        "text" => Ok(Emit::Text),
+        "dot" => Ok(Emit::Dot),
+        _ => Err(format!("unknown output format `{format}`, expected json, text or dot")),
+    }
+}
+
+fn parse_args(args: &[String]) -> std::result::Result<Options, String> {
+    let mut pipeline = String::new();
+    let mut opts = Options {
+        passes: PassManager::new(),
+        emit: Emit::Json,
+        dump_cfg: false,
+        dump_loops: false,
+        input: None,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next().cloned())
+                .ok_or_else(|| format!("missing value for `{flag}`"))
+        };
+        match flag {
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                exit(0);
+            }
+            "--list-passes" => {
+                for info in registry() {
+                    println!("{:<8}{}", info.name, info.description);
+                }
+                exit(0);
+            }
+            "-p" | "--passes" => {
+                pipeline.push_str(&value()?);
+                pipeline.push(',');
+            }
+            "--emit" => opts.emit = parse_emit(&value()?)?,
+            "--dump-cfg" => opts.dump_cfg = true,
+            "--dump-loops" => opts.dump_loops = true,
+            _ if flag.starts_with('-') && flag != "-" => return Err(format!("unknown option `{flag}`")),
+            _ => {
+                if opts.input.is_some() {
+                    return Err("only one input file is supported".to_string());
+                }
+                opts.input = Some(arg.clone());
+            }
+        }
+    }
+    opts.passes = PassManager::from_pipeline(&pipeline).map_err(|e| format!("{e}, see --list-passes"))?;
+    Ok(opts)
+}
+
+fn read_input(input: Option<&str>) -> Result<String> {
+    match input {
+        Some(path) if path != "-" => Ok(fs::read_to_string(path)?),
+        _ => {
+            let mut s = String::new();
+            stdin().read_to_string(&mut s)?;
+            Ok(s)
+        }
+    }
+}
+
+fn run(mut opts: Options) -> Result<()> {
+    let src = read_input(opts.input.as_deref())?;
+    // json programs always start with an object, text ones with a function
+    let mut cfg = if src.trim_start().starts_with('{') {
+        BrilCFG::from_json(&src)?
+    } else {
+        BrilCFG::from_text(&src)?
+    };
+    opts.passes.run(&mut cfg)?;
+
+    if opts.dump_cfg {
+        for func in &cfg.functions {
+            println!("{func}");
+        }
+        return Ok(());
+    }
+    if opts.dump_loops {
+        for func in &cfg.functions {
+            println!("@{}:", func.name);
+            print!("{}", LoopForest::compute(func).display(func));
         }
+        return Ok(());
     }
-    let bril: Bril = serde_json::from_str(&s).unwrap();
-    let mut cfg = BrilCFG::new(bril);
-    cfg.parse_blocks();
-    for block in cfg.blocks {
-        println!("{block}");
+    match opts.emit {
+        Emit::Json => println!("{}", serde_json::to_string(&cfg.to_bril()).expect("bril is always serializable")),
+        Emit::Text => print!("{}", cfg.to_text()),
+        Emit::Dot => print!("{}", cfg.to_dot()),
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let opts = parse_args(&args).unwrap_or_else(|e| {
+        eprintln!("error: {e}\n\n{USAGE}");
+        exit(2);
+    });
+    if let Err(e) = run(opts) {
+        eprintln!("error: {e}");
+        exit(1);
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn cli_args() {
+        let opts = parse_args(&args("-p lvn,dce* --emit=text prog.json")).unwrap();
+        assert_eq!(opts.emit, Emit::Text);
+        assert_eq!(opts.input.as_deref(), Some("prog.json"));
+        assert!(!opts.dump_cfg);
+
+        let opts = parse_args(&args("--passes dce --emit json --dump-cfg")).unwrap();
+        assert_eq!(opts.emit, Emit::Json);
+        assert_eq!(parse_args(&args("--emit=dot")).unwrap().emit, Emit::Dot);
+        assert!(opts.dump_cfg);
+        assert!(!opts.dump_loops);
+        assert!(opts.input.is_none());
+        assert!(parse_args(&args("--dump-loops")).unwrap().dump_loops);
+    }
+
+    #[test]
+    fn cli_args_errors() {
+        assert!(parse_args(&args("-p nope")).is_err());
+        assert!(parse_args(&args("--emit=xml")).is_err());
+        assert!(parse_args(&args("--emit")).is_err());
+        assert!(parse_args(&args("--frobnicate")).is_err());
+        assert!(parse_args(&args("a.json b.json")).is_err());
+    }
+}
@@ -1,11 +1,39 @@
-use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+use serde::{
+    de::{self, IntoDeserializer, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+
+mod printer;
+mod text;
+
+pub use text::ParseError;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Bril {
     pub(crate) functions: Vec<Function>
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl Bril {
+    pub fn from_text(text: &str) -> Result<Self, ParseError> {
+        text::parse(text)
+    }
+}
+
+impl FromStr for Bril {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        text::parse(s)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Function {
     pub(crate) name: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -13,9 +41,22 @@ pub struct Function {
     #[serde(rename="type")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) typ: Option<Type>,
+    #[serde(deserialize_with = "deserialize_instrs")]
     pub(crate) instrs: Vec<Instr>
 }
 
+// serde_json hands integers beyond u64 over as floats, their exact value
+// is lost so they can't wrap like in brili. reject them like the text parser
+fn deserialize_instrs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Instr>, D::Error> {
+    let instrs = Vec::<Instr>::deserialize(deserializer)?;
+    for instr in &instrs {
+        if let Instr::Instruction { op: Opcode::cst, typ: Some(Type::int), value: Some(Literal::Float(v)), .. } = instr {
+            return Err(de::Error::custom(format!("integer literal `{v}` out of range")));
+        }
+    }
+    Ok(instrs)
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 #[serde(untagged)]
 pub enum Instr {
@@ -52,6 +93,18 @@ impl Instr {
             value: None,
         }
     }
+
+    pub fn new_const_instr(dest: &str, value: Literal, typ: Type) -> Self {
+        Instr::Instruction {
+            op: Opcode::cst,
+            dest: Some(dest.to_string()),
+            args: None,
+            typ: Some(typ),
+            funcs: None,
+            labels: None,
+            value: Some(value),
+        }
+    }
 }
 
 #[allow(non_camel_case_types)]
@@ -77,31 +130,152 @@ pub enum Opcode {
     print,
     nop,
     #[serde(rename="const")]
-    cst
+    cst,
+    // memory extension
+    alloc,
+    free,
+    store,
+    load,
+    ptradd,
+    // floating point extension
+    fadd,
+    fmul,
+    fsub,
+    fdiv,
+    feq,
+    flt,
+    fgt,
+    fle,
+    fge,
+    // ssa extension
+    phi,
+}
+
+impl Opcode {
+    // pure instructions only compute their dest, so they can be shared
+    // when recomputed. removing one that can trap needs more care
+    pub fn is_pure(&self) -> bool {
+        use Opcode::*;
+        match self {
+            add | mul | sub | div | eq | lt | gt | le | ge | not | and | or | id | cst | ptradd => true,
+            fadd | fmul | fsub | fdiv | feq | flt | fgt | fle | fge => true,
+            phi => true,
+            jmp | br | call | ret | print | nop | alloc | free | store | load => false,
+        }
+    }
+
+    // integer division by zero aborts the program
+    pub fn can_trap(&self) -> bool {
+        *self == Opcode::div
+    }
+}
+
+impl FromStr for Opcode {
+    type Err = serde::de::value::Error;
+
+    // reuse the serde names so the text and json format never disagree
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Opcode::deserialize(s.into_deserializer())
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Arg {
-    name: String,
+    pub(crate) name: String,
     #[serde(rename="type")]
-    typ: Type
+    pub(crate) typ: Type
 }
 
 #[allow(non_camel_case_types)]
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Type {
     int,
-    bool
+    bool,
+    float,
+    ptr(Box<Type>),
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Serialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum Literal {
-    Number(usize),
+    Number(i64),
+    Float(f64),
     Bool(bool)
 }
 
+// a derived untagged deserializer would turn integers above i64::MAX into
+// floats, brili instead wraps them to 64 bits (BigInt.asIntN(64, ..)).
+// beyond u64::MAX they still arrive as floats, see `deserialize_instrs`
+impl<'de> Deserialize<'de> for Literal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct LiteralVisitor;
+
+        impl Visitor<'_> for LiteralVisitor {
+            type Value = Literal;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a number or a boolean")
+            }
+
+            fn visit_bool<E: de::Error>(self, v: bool) -> Result<Literal, E> {
+                Ok(Literal::Bool(v))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Literal, E> {
+                Ok(Literal::Number(v))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Literal, E> {
+                Ok(Literal::Number(v as i64))
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Literal, E> {
+                Ok(Literal::Float(v))
+            }
+        }
+
+        deserializer.deserialize_any(LiteralVisitor)
+    }
+}
+
+impl Literal {
+    // json may write a float constant without a fraction, e.g. `"value": 1`
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Literal::Number(n) => Some(*n as f64),
+            Literal::Float(f) => Some(*f),
+            Literal::Bool(_) => None,
+        }
+    }
+}
+
+// floats compare by bit pattern, so `0.0` and `-0.0` are different
+// constants and a NaN constant is equal to itself
+impl PartialEq for Literal {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Literal::Number(a), Literal::Number(b)) => a == b,
+            (Literal::Float(a), Literal::Float(b)) => a.to_bits() == b.to_bits(),
+            (Literal::Bool(a), Literal::Bool(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Literal {}
+
+impl Hash for Literal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Literal::Number(n) => n.hash(state),
+            Literal::Float(f) => f.to_bits().hash(state),
+            Literal::Bool(b) => b.hash(state),
+        }
+    }
+}
+
 
 
 
@@ -134,7 +308,7 @@ mod tests {
         // let bril = Bril{functions: vec![func]};
         // let bril_str = serde_json::to_string(&bril).unwrap();
         // println!("bri: {bril_str}");
-        serde_json::from_str::<Bril>(&s).expect("cannot parse functions");
+        serde_json::from_str::<Bril>(s).expect("cannot parse functions");
     }
 
     #[test]
@@ -147,7 +321,7 @@ mod tests {
           "value": 1
         }
 "#;
-        serde_json::from_str::<Instr>(&s).expect("cannot parse functions");
+        serde_json::from_str::<Instr>(s).expect("cannot parse functions");
     }
 
     #[test]
@@ -157,7 +331,107 @@ mod tests {
           "label": "hello"
         }
 "#;
-        serde_json::from_str::<Instr>(&s).expect("cannot parse functions");
+        serde_json::from_str::<Instr>(s).expect("cannot parse functions");
+    }
+
+    #[test]
+    fn bril_json_parse_memory() {
+        let s = r#"
+        {
+          "args": ["size"],
+          "dest": "p",
+          "op": "alloc",
+          "type": {"ptr": {"ptr": "int"}}
+        }
+"#;
+        let instr = serde_json::from_str::<Instr>(s).expect("cannot parse alloc");
+        if let Instr::Instruction { op, typ, .. } = &instr {
+            assert_eq!(op, &Opcode::alloc);
+            let int_ptr = Type::ptr(Box::new(Type::int));
+            assert_eq!(typ, &Some(Type::ptr(Box::new(int_ptr))));
+        } else {
+            panic!("expected alloc");
+        }
+        let json = serde_json::to_string(&instr).unwrap();
+        assert!(json.contains(r#""type":{"ptr":{"ptr":"int"}}"#));
+        assert_eq!(instr.to_string(), "p: ptr<ptr<int>> = alloc size;");
+        assert_eq!(Bril::from_text("@main { p: ptr<ptr<int>> = alloc size; }").unwrap().functions[0].instrs[0], instr);
+    }
+
+    #[test]
+    fn bril_json_parse_phi() {
+        let s = r#"{ "args": ["a", "__undefined"], "dest": "x", "labels": ["l", "r"], "op": "phi", "type": "int" }"#;
+        let instr = serde_json::from_str::<Instr>(s).expect("cannot parse phi");
+        assert!(matches!(&instr, Instr::Instruction { op: Opcode::phi, .. }));
+        assert_eq!(instr.to_string(), "x: int = phi a __undefined .l .r;");
+        assert_eq!(Bril::from_text("@main { x: int = phi a __undefined .l .r; }").unwrap().functions[0].instrs[0], instr);
     }
 
+
+    #[test]
+    fn bril_json_parse_float() {
+        let s = r#"
+{
+  "functions": [
+    {
+      "instrs": [
+        { "dest": "a", "op": "const", "type": "float", "value": 1.5 },
+        { "dest": "b", "op": "const", "type": "float", "value": 2 },
+        { "args": ["a", "b"], "dest": "c", "op": "fadd", "type": "float" },
+        { "args": ["c", "a"], "dest": "d", "op": "flt", "type": "bool" }
+      ],
+      "name": "main"
+    }
+  ]
+}
+"#;
+        let bril = serde_json::from_str::<Bril>(s).expect("cannot parse float program");
+        let instrs = &bril.functions[0].instrs;
+        if let Instr::Instruction { value: Some(value), .. } = &instrs[0] {
+            assert_eq!(value, &Literal::Float(1.5));
+        } else {
+            panic!("expected const");
+        }
+        if let Instr::Instruction { value: Some(value), .. } = &instrs[1] {
+            assert_eq!(value.as_f64(), Some(2.0));
+        } else {
+            panic!("expected const");
+        }
+        assert_ne!(Literal::Float(0.0), Literal::Float(-0.0));
+        assert_eq!(Literal::Float(f64::NAN), Literal::Float(f64::NAN));
+
+        let text = "@main {\n  a: float = const 1.0;\n  b: float = const -2.5e-3;\n  c: float = fdiv a b;\n}\n";
+        let bril = Bril::from_text(text).unwrap();
+        assert_eq!(bril.to_string(), text.replace("-2.5e-3", "-0.0025"));
+    }
+
+    #[test]
+    fn bril_json_parse_int_literal() {
+        let parse = |s: &str| serde_json::from_str::<Literal>(s).unwrap();
+        assert_eq!(parse("-1"), Literal::Number(-1));
+        assert_eq!(parse("9223372036854775807"), Literal::Number(i64::MAX));
+        // wraps like brili
+        assert_eq!(parse("18446744073709551615"), Literal::Number(-1));
+        assert_eq!(parse("true"), Literal::Bool(true));
+        assert_eq!(parse("0.5"), Literal::Float(0.5));
+        assert_eq!(serde_json::to_string(&Literal::Number(-3)).unwrap(), "-3");
+        assert_eq!(serde_json::to_string(&Literal::Bool(false)).unwrap(), "false");
+
+        let bril = Bril::from_text("@main {\n  x: int = const -1;\n  print x;\n}\n").unwrap();
+        assert_eq!(bril.to_string(), "@main {\n  x: int = const -1;\n  print x;\n}\n");
+
+        // past u64::MAX the json and the text parser both reject it
+        let json = r#"{"functions": [{"name": "main", "instrs": [
+            {"op": "const", "dest": "y", "type": "int", "value": 18446744073709551617}
+        ]}]}"#;
+        let err = serde_json::from_str::<Bril>(json).unwrap_err();
+        assert!(err.to_string().contains("integer literal `18446744073709552000` out of range"));
+        let err = Bril::from_text("@main {\n  y: int = const 18446744073709551617;\n}").unwrap_err();
+        assert_eq!((err.line, err.msg.as_str()), (2, "integer literal `18446744073709551617` out of range"));
+        // a float const can still be written that big
+        let json = json.replace("\"int\"", "\"float\"");
+        let bril = serde_json::from_str::<Bril>(&json).unwrap();
+        assert_eq!(bril.to_string(), "@main {\n  y: float = const 1.8446744073709552e19;\n}\n");
+        assert!(Bril::from_text(&bril.to_string()).is_ok());
+    }
 }
@@ -0,0 +1,178 @@
+// canonical text form of bril, the inverse of `text::parse`.
+// the layout follows bril2txt so golden files stay comparable
+use std::fmt::{self, Display};
+
+use super::{Arg, Bril, Function, Instr, Literal, Opcode, Type};
+
+impl Display for Bril {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, func) in self.functions.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{func}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "@{}", self.name)?;
+        if let Some(args) = &self.args {
+            let args = args.iter().map(Arg::to_string).collect::<Vec<_>>();
+            write!(f, "({})", args.join(", "))?;
+        }
+        if let Some(typ) = &self.typ {
+            write!(f, ": {typ}")?;
+        }
+        writeln!(f, " {{")?;
+        for instr in &self.instrs {
+            match instr {
+                Instr::Label { .. } => writeln!(f, "{instr}")?,
+                Instr::Instruction { .. } => writeln!(f, "  {instr}")?,
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.typ)
+    }
+}
+
+impl Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instr::Label { label } => write!(f, ".{label}:"),
+            Instr::Instruction {
+                op,
+                dest,
+                typ,
+                args,
+                funcs,
+                labels,
+                value,
+            } => {
+                if let Some(dest) = dest {
+                    write!(f, "{dest}")?;
+                    if let Some(typ) = typ {
+                        write!(f, ": {typ}")?;
+                    }
+                    write!(f, " = ")?;
+                }
+                write!(f, "{op}")?;
+                if let Some(value) = value {
+                    write!(f, " {value}")?;
+                }
+                for func in funcs.iter().flatten() {
+                    write!(f, " @{func}")?;
+                }
+                for arg in args.iter().flatten() {
+                    write!(f, " {arg}")?;
+                }
+                for label in labels.iter().flatten() {
+                    write!(f, " .{label}")?;
+                }
+                write!(f, ";")
+            }
+        }
+    }
+}
+
+impl Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the serde name is the one bril uses in both formats
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(name)) => write!(f, "{name}"),
+            _ => Err(fmt::Error),
+        }
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::int => write!(f, "int"),
+            Type::bool => write!(f, "bool"),
+            Type::float => write!(f, "float"),
+            Type::ptr(typ) => write!(f, "ptr<{typ}>"),
+        }
+    }
+}
+
+impl Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Literal::Number(n) => write!(f, "{n}"),
+            // debug formatting always keeps the fraction, `1.0` not `1`
+            Literal::Float(x) => write!(f, "{x:?}"),
+            Literal::Bool(b) => write!(f, "{b}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::text::parse;
+    use crate::cfg::BrilCFG;
+
+    #[test]
+    fn print_text() {
+        let text = r#"@main(n: int, flag: bool): int {
+  v: int = const 4;
+  t: bool = const true;
+  sum: int = add v n;
+  br flag .then .else;
+.then:
+  x: int = call @helper sum;
+  print x;
+  jmp .end;
+.else:
+  call @helper v;
+.end:
+  nop;
+  ret sum;
+}
+
+@helper(a: int): int {
+  ret a;
+}
+"#;
+        let bril = parse(text).unwrap();
+        assert_eq!(bril.to_string(), text);
+    }
+
+    #[test]
+    fn print_round_trip() {
+        let text = r#"@main { v: int = const 1; jmp .tmp0; .tmp0: print v; ret; }"#;
+        let bril = parse(text).unwrap();
+        let printed = bril.to_string();
+        assert!(printed.contains(".tmp0:\n"));
+        assert_eq!(parse(&printed).unwrap(), bril);
+    }
+
+    #[test]
+    fn print_synthesized_labels() {
+        // the block after the jump has no label of its own, the cfg names it
+        let text = r#"@main(c: bool) {
+  v: int = const 1;
+  br c .a .b;
+.a:
+  jmp .b;
+  v: int = const 2;
+  print v;
+.b:
+  print v;
+}"#;
+        let cfg = BrilCFG::from_text(text).unwrap();
+        let printed = cfg.to_text();
+        println!("printed: {printed}");
+        assert!(printed.contains("  jmp .b;\n.tmp0:\n  v: int = const 2;"));
+        assert_eq!(parse(&printed).unwrap(), cfg.to_bril());
+        // and they stay the same on the next round
+        assert_eq!(BrilCFG::from_text(&printed).unwrap().to_text(), printed);
+    }
+}
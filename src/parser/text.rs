@@ -0,0 +1,502 @@
+// lexer and parser for bril's text format, see
+// https://capra.cs.cornell.edu/bril/tools/text.html
+use std::fmt::{self, Display};
+
+use super::{Arg, Bril, Function, Instr, Literal, Opcode, Type};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub col: usize,
+    pub msg: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.col, self.msg)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    // @name
+    Func(String),
+    // .name
+    Label(String),
+    // kept as text, the parser decides what kind of literal it is
+    Number(String),
+    LBrace,
+    RBrace,
+    LParen,
+    RParen,
+    Colon,
+    Semi,
+    Eq,
+    Comma,
+    Lt,
+    Gt,
+    Eof,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(s) => write!(f, "`{s}`"),
+            Token::Func(s) => write!(f, "`@{s}`"),
+            Token::Label(s) => write!(f, "`.{s}`"),
+            Token::Number(s) => write!(f, "`{s}`"),
+            Token::LBrace => write!(f, "`{{`"),
+            Token::RBrace => write!(f, "`}}`"),
+            Token::LParen => write!(f, "`(`"),
+            Token::RParen => write!(f, "`)`"),
+            Token::Colon => write!(f, "`:`"),
+            Token::Semi => write!(f, "`;`"),
+            Token::Eq => write!(f, "`=`"),
+            Token::Comma => write!(f, "`,`"),
+            Token::Lt => write!(f, "`<`"),
+            Token::Gt => write!(f, "`>`"),
+            Token::Eof => write!(f, "end of input"),
+        }
+    }
+}
+
+struct Spanned {
+    token: Token,
+    line: usize,
+    col: usize,
+}
+
+struct Lexer<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+    col: usize,
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '%'
+}
+
+fn is_ident_char(c: char) -> bool {
+    is_ident_start(c) || c.is_ascii_digit() || c == '.'
+}
+
+impl<'a> Lexer<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            chars: text.chars().peekable(),
+            line: 1,
+            col: 1,
+        }
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn eat_while<F: Fn(char) -> bool>(&mut self, f: F) -> String {
+        let mut s = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !f(c) {
+                break;
+            }
+            s.push(c);
+            self.bump();
+        }
+        s
+    }
+
+    fn error(&self, line: usize, col: usize, msg: String) -> ParseError {
+        ParseError { line, col, msg }
+    }
+
+    fn tokenize(mut self) -> Result<Vec<Spanned>, ParseError> {
+        let mut tokens = vec![];
+        loop {
+            // skip whitespace and comments
+            while let Some(&c) = self.chars.peek() {
+                if c.is_whitespace() {
+                    self.bump();
+                } else if c == '#' {
+                    self.eat_while(|c| c != '\n');
+                } else {
+                    break;
+                }
+            }
+            let (line, col) = (self.line, self.col);
+            let Some(c) = self.bump() else {
+                tokens.push(Spanned { token: Token::Eof, line, col });
+                return Ok(tokens);
+            };
+            let token = match c {
+                '{' => Token::LBrace,
+                '}' => Token::RBrace,
+                '(' => Token::LParen,
+                ')' => Token::RParen,
+                ':' => Token::Colon,
+                ';' => Token::Semi,
+                '=' => Token::Eq,
+                ',' => Token::Comma,
+                '<' => Token::Lt,
+                '>' => Token::Gt,
+                '@' | '.' => {
+                    let name = self.eat_while(is_ident_char);
+                    if name.is_empty() {
+                        return Err(self.error(line, col, format!("expected a name after `{c}`")));
+                    }
+                    if c == '@' {
+                        Token::Func(name)
+                    } else {
+                        Token::Label(name)
+                    }
+                }
+                c if c == '-' || c.is_ascii_digit() => {
+                    let mut num = c.to_string();
+                    loop {
+                        num.push_str(&self.eat_while(|c| c.is_ascii_alphanumeric() || c == '.'));
+                        // exponent sign, e.g. `1e-5`
+                        match self.chars.peek() {
+                            Some(&c) if (c == '-' || c == '+') && num.ends_with(['e', 'E']) => {
+                                num.push(c);
+                                self.bump();
+                            }
+                            _ => break,
+                        }
+                    }
+                    if num == "-" {
+                        return Err(self.error(line, col, "expected a number after `-`".to_string()));
+                    }
+                    Token::Number(num)
+                }
+                c if is_ident_start(c) => {
+                    let mut name = c.to_string();
+                    name.push_str(&self.eat_while(is_ident_char));
+                    Token::Ident(name)
+                }
+                c => return Err(self.error(line, col, format!("unexpected character `{c}`"))),
+            };
+            tokens.push(Spanned { token, line, col });
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Spanned>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].token
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].token.clone();
+        // never step past eof
+        if self.pos + 1 < self.tokens.len() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn error_at(&self, pos: usize, msg: String) -> ParseError {
+        let Spanned { line, col, .. } = self.tokens[pos];
+        ParseError { line, col, msg }
+    }
+
+    fn error(&self, msg: String) -> ParseError {
+        self.error_at(self.pos, msg)
+    }
+
+    fn unexpected(&self, expected: &str) -> ParseError {
+        self.error(format!("expected {expected}, found {}", self.peek()))
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), ParseError> {
+        if self.peek() == &token {
+            self.next();
+            Ok(())
+        } else {
+            Err(self.unexpected(&token.to_string()))
+        }
+    }
+
+    fn ident(&mut self, expected: &str) -> Result<String, ParseError> {
+        if let Token::Ident(name) = self.peek() {
+            let name = name.clone();
+            self.next();
+            Ok(name)
+        } else {
+            Err(self.unexpected(expected))
+        }
+    }
+
+    fn program(&mut self) -> Result<Bril, ParseError> {
+        let mut functions = vec![];
+        while self.peek() != &Token::Eof {
+            functions.push(self.function()?);
+        }
+        Ok(Bril { functions })
+    }
+
+    fn function(&mut self) -> Result<Function, ParseError> {
+        let Token::Func(name) = self.peek().clone() else {
+            return Err(self.unexpected("a function name like `@main`"));
+        };
+        self.next();
+        let mut args = None;
+        if self.peek() == &Token::LParen {
+            self.next();
+            let mut list = vec![];
+            while self.peek() != &Token::RParen {
+                if !list.is_empty() {
+                    self.expect(Token::Comma)?;
+                }
+                let name = self.ident("an argument name")?;
+                self.expect(Token::Colon)?;
+                let typ = self.typ()?;
+                list.push(Arg { name, typ });
+            }
+            self.next();
+            if !list.is_empty() {
+                args = Some(list);
+            }
+        }
+        let mut typ = None;
+        if self.peek() == &Token::Colon {
+            self.next();
+            typ = Some(self.typ()?);
+        }
+        self.expect(Token::LBrace)?;
+        let mut instrs = vec![];
+        while self.peek() != &Token::RBrace {
+            instrs.push(self.instr()?);
+        }
+        self.next();
+        Ok(Function {
+            name,
+            args,
+            typ,
+            instrs,
+        })
+    }
+
+    fn typ(&mut self) -> Result<Type, ParseError> {
+        let start = self.pos;
+        let name = self.ident("a type")?;
+        match name.as_str() {
+            "int" => Ok(Type::int),
+            "bool" => Ok(Type::bool),
+            "float" => Ok(Type::float),
+            "ptr" => {
+                self.expect(Token::Lt)?;
+                let typ = self.typ()?;
+                self.expect(Token::Gt)?;
+                Ok(Type::ptr(Box::new(typ)))
+            }
+            _ => Err(self.error_at(start, format!("unknown type `{name}`"))),
+        }
+    }
+
+    fn instr(&mut self) -> Result<Instr, ParseError> {
+        if let Token::Label(label) = self.peek() {
+            let label = label.clone();
+            self.next();
+            self.expect(Token::Colon)?;
+            return Ok(Instr::Label { label });
+        }
+        let first = self.ident("an instruction")?;
+        let (dest, typ) = match self.peek() {
+            Token::Colon => {
+                self.next();
+                let typ = self.typ()?;
+                self.expect(Token::Eq)?;
+                (Some(first), Some(typ))
+            }
+            Token::Eq => {
+                self.next();
+                (Some(first), None)
+            }
+            _ => {
+                // effect operation, the identifier we just read is the opcode
+                self.pos -= 1;
+                (None, None)
+            }
+        };
+        self.operation(dest, typ)
+    }
+
+    fn operation(&mut self, dest: Option<String>, typ: Option<Type>) -> Result<Instr, ParseError> {
+        let op_pos = self.pos;
+        let op_name = self.ident("an opcode")?;
+        let op: Opcode = op_name
+            .parse()
+            .map_err(|_| self.error_at(op_pos, format!("unknown opcode `{op_name}`")))?;
+        if dest.is_some() && matches!(
+            op,
+            Opcode::jmp | Opcode::br | Opcode::ret | Opcode::print | Opcode::nop | Opcode::store | Opcode::free
+        ) {
+            return Err(self.error_at(op_pos, format!("`{op_name}` does not produce a value")));
+        }
+
+        let mut value = None;
+        let mut args = vec![];
+        let mut funcs = vec![];
+        let mut labels = vec![];
+        if op == Opcode::cst {
+            if dest.is_none() {
+                return Err(self.error_at(op_pos, "`const` needs a destination".to_string()));
+            }
+            value = Some(self.literal(typ.as_ref())?);
+        } else {
+            loop {
+                match self.peek().clone() {
+                    Token::Ident(arg) => args.push(arg),
+                    Token::Func(func) => funcs.push(func),
+                    Token::Label(label) => labels.push(label),
+                    _ => break,
+                }
+                self.next();
+            }
+        }
+        self.expect(Token::Semi)?;
+
+        let non_empty = |v: Vec<String>| if v.is_empty() { None } else { Some(v) };
+        Ok(Instr::Instruction {
+            op,
+            dest,
+            typ,
+            args: non_empty(args),
+            funcs: non_empty(funcs),
+            labels: non_empty(labels),
+            value,
+        })
+    }
+
+    fn literal(&mut self, typ: Option<&Type>) -> Result<Literal, ParseError> {
+        if typ == Some(&Type::float) {
+            let text = match self.peek().clone() {
+                // `inf` and `NaN` are written as plain words
+                Token::Number(s) | Token::Ident(s) => s,
+                _ => return Err(self.unexpected("a float literal")),
+            };
+            let lit = text
+                .parse()
+                .map_err(|_| self.error(format!("invalid float literal `{text}`")))?;
+            self.next();
+            return Ok(Literal::Float(lit));
+        }
+        match self.peek().clone() {
+            Token::Ident(s) if s == "true" => {
+                self.next();
+                Ok(Literal::Bool(true))
+            }
+            Token::Ident(s) if s == "false" => {
+                self.next();
+                Ok(Literal::Bool(false))
+            }
+            Token::Number(s) => {
+                // like the json side, literals up to u64::MAX wrap to 64 bits
+                let lit = s
+                    .parse::<i64>()
+                    .or_else(|_| s.parse::<u64>().map(|n| n as i64))
+                    .map_err(|_| self.error(format!("integer literal `{s}` out of range")))?;
+                self.next();
+                Ok(Literal::Number(lit))
+            }
+            _ => Err(self.unexpected("a literal")),
+        }
+    }
+}
+
+pub fn parse(text: &str) -> Result<Bril, ParseError> {
+    let tokens = Lexer::new(text).tokenize()?;
+    Parser { tokens, pos: 0 }.program()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_text_program() {
+        let text = r#"
+# leading comment
+@main(n: int, flag: bool): int {
+  v: int = const 4;
+  t: bool = const true;
+  sum: int = add v n;
+  br flag .then .else;
+.then:
+  x: int = call @helper sum;
+  print x;
+  jmp .end;
+.else:
+  call @helper v;
+.end:
+  nop;
+  ret sum;
+}
+
+@helper(a: int): int {
+  ret a;
+}
+"#;
+        let bril = parse(text).expect("cannot parse text");
+        assert_eq!(bril.functions.len(), 2);
+        let main = &bril.functions[0];
+        assert_eq!(main.name, "main");
+        assert_eq!(main.typ, Some(Type::int));
+        assert_eq!(main.args.as_ref().map(|a| a.len()), Some(2));
+        assert_eq!(main.instrs.len(), 13);
+        assert_eq!(main.instrs[4], Instr::Label { label: "then".to_string() });
+        if let Instr::Instruction { op, args, labels, .. } = &main.instrs[3] {
+            assert_eq!(op, &Opcode::br);
+            assert_eq!(args, &Some(vec!["flag".to_string()]));
+            assert_eq!(labels, &Some(vec!["then".to_string(), "else".to_string()]));
+        } else {
+            panic!("expected br");
+        }
+        if let Instr::Instruction { op, dest, funcs, .. } = &main.instrs[5] {
+            assert_eq!(op, &Opcode::call);
+            assert_eq!(dest, &Some("x".to_string()));
+            assert_eq!(funcs, &Some(vec!["helper".to_string()]));
+        } else {
+            panic!("expected call");
+        }
+    }
+
+    #[test]
+    fn parse_text_matches_json() {
+        let text = r#"@main {
+  v0: int = const 1;
+  print v0;
+}"#;
+        let json = r#"{"functions":[{"name":"main","instrs":[{"op":"const","dest":"v0","type":"int","value":1},{"op":"print","args":["v0"]}]}]}"#;
+        let bril = parse(text).unwrap();
+        assert_eq!(serde_json::to_string(&bril).unwrap(), json);
+    }
+
+    #[test]
+    fn parse_text_error_position() {
+        let text = "@main {\n  v: int = const 1;\n  x: int = frob v;\n}";
+        let err = parse(text).unwrap_err();
+        assert_eq!((err.line, err.col), (3, 12));
+        assert!(err.msg.contains("frob"));
+
+        let err = parse("@main {\n  v: int = const 1\n}").unwrap_err();
+        assert_eq!((err.line, err.col), (3, 1));
+
+        let err = parse("@main {\n  v: int = const $;\n}").unwrap_err();
+        assert_eq!((err.line, err.col), (2, 18));
+    }
+}
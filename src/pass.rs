@@ -0,0 +1,384 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+};
+
+use crate::{
+    cfg::{Block, BrilCFG, FunctionCFG},
+    constprop::ConstantPropagation,
+    dce::{Dce, TrivialDce},
+    error::{Error, Result},
+    induction::StrengthReduction,
+    licm::Licm,
+    lvn::Lvn,
+    sccp::Sccp,
+    ssa::{self, FromSsa, ToSsa},
+    unroll::Unroll,
+};
+
+// a fixpoint that takes longer than this is most likely two passes undoing
+// each other
+const MAX_FIXPOINT_ITERATIONS: usize = 100;
+
+pub enum Granularity {
+    Block,
+    Function,
+}
+
+// a transformation over the cfg. every `run_*` returns whether it changed
+// anything, which drives both `*` fixpoints and analysis invalidation
+pub trait Pass {
+    fn name(&self) -> &'static str;
+
+    fn granularity(&self) -> Granularity {
+        Granularity::Block
+    }
+
+    // a function in ssa form is still in ssa form after the pass, checked
+    // with `ssa::verify` when the pass manager verifies ssa
+    fn preserves_ssa(&self) -> bool {
+        false
+    }
+
+    fn run_on_block(&mut self, _block: &mut Block) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn run_on_function(&mut self, _func: &mut FunctionCFG, _cache: &mut AnalysisCache) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+pub struct PassInfo {
+    pub name: &'static str,
+    pub description: &'static str,
+    create: fn() -> Box<dyn Pass>,
+}
+
+pub fn registry() -> Vec<PassInfo> {
+    vec![
+        PassInfo {
+            name: "lvn",
+            description: "local value numbering",
+            create: || Box::new(Lvn),
+        },
+        PassInfo {
+            name: "tdce",
+            description: "trivial dead code elimination",
+            create: || Box::new(TrivialDce),
+        },
+        PassInfo {
+            name: "dce",
+            description: "dead code elimination based on liveness",
+            create: || Box::new(Dce),
+        },
+        PassInfo {
+            name: "constprop",
+            description: "global constant propagation and folding",
+            create: || Box::new(ConstantPropagation),
+        },
+        PassInfo {
+            name: "sccp",
+            description: "sparse conditional constant propagation, needs ssa form",
+            create: || Box::new(Sccp),
+        },
+        PassInfo {
+            name: "licm",
+            description: "loop invariant code motion into loop preheaders",
+            create: || Box::new(Licm),
+        },
+        PassInfo {
+            name: "strength",
+            description: "strength reduction of induction variables, out of ssa form",
+            create: || Box::new(StrengthReduction),
+        },
+        PassInfo {
+            name: "unroll",
+            description: "unroll loops with a constant trip count, out of ssa form",
+            create: || Box::new(Unroll::default()),
+        },
+        PassInfo {
+            name: "to_ssa",
+            description: "convert into ssa form",
+            create: || Box::new(ToSsa),
+        },
+        PassInfo {
+            name: "from_ssa",
+            description: "convert out of ssa form",
+            create: || Box::new(FromSsa),
+        },
+    ]
+}
+
+pub fn create_pass(name: &str) -> Result<Box<dyn Pass>> {
+    registry()
+        .into_iter()
+        .find(|info| info.name == name)
+        .map(|info| (info.create)())
+        .ok_or_else(|| Error::Pipeline(format!("unknown pass `{name}`")))
+}
+
+// analysis results keyed by function and analysis type, computed lazily and
+// dropped as soon as a pass changes the function they were computed on
+#[derive(Default)]
+pub struct AnalysisCache {
+    entries: HashMap<(String, TypeId), Box<dyn Any>>,
+}
+
+impl AnalysisCache {
+    pub fn get_or_compute<T: Any, F: FnOnce() -> T>(&mut self, func: &str, compute: F) -> &T {
+        self.entries
+            .entry((func.to_string(), TypeId::of::<T>()))
+            .or_insert_with(|| Box::new(compute()))
+            .downcast_ref()
+            .expect("analysis cache entry with the wrong type")
+    }
+
+    pub fn is_cached<T: Any>(&self, func: &str) -> bool {
+        self.entries.contains_key(&(func.to_string(), TypeId::of::<T>()))
+    }
+
+    pub fn invalidate_function(&mut self, func: &str) {
+        self.entries.retain(|(name, _), _| name != func);
+    }
+
+    pub fn invalidate(&mut self) {
+        self.entries.clear();
+    }
+}
+
+struct Step {
+    pass: Box<dyn Pass>,
+    // `name*` in the pipeline, rerun until nothing changes
+    fixpoint: bool,
+}
+
+pub struct PassManager {
+    steps: Vec<Step>,
+    cache: AnalysisCache,
+    // run `ssa::verify` after passes that preserve ssa, on by default in
+    // debug builds
+    verify_ssa: bool,
+}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        Self {
+            steps: vec![],
+            cache: AnalysisCache::default(),
+            verify_ssa: cfg!(debug_assertions),
+        }
+    }
+}
+
+impl PassManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_verify_ssa(&mut self, verify_ssa: bool) -> &mut Self {
+        self.verify_ssa = verify_ssa;
+        self
+    }
+
+    // parse a pipeline like `lvn,dce*`
+    pub fn from_pipeline(pipeline: &str) -> Result<Self> {
+        let mut pm = Self::new();
+        for name in pipeline.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            let (name, fixpoint) = match name.strip_suffix('*') {
+                Some(name) => (name, true),
+                None => (name, false),
+            };
+            pm.add(create_pass(name)?, fixpoint);
+        }
+        Ok(pm)
+    }
+
+    pub fn add(&mut self, pass: Box<dyn Pass>, fixpoint: bool) -> &mut Self {
+        self.steps.push(Step { pass, fixpoint });
+        self
+    }
+
+    pub fn run(&mut self, cfg: &mut BrilCFG) -> Result<bool> {
+        let mut changed = false;
+        for step in self.steps.iter_mut() {
+            let mut iterations = 0;
+            loop {
+                let step_changed = Self::run_step(step.pass.as_mut(), cfg, &mut self.cache, self.verify_ssa)?;
+                changed |= step_changed;
+                if !step.fixpoint || !step_changed {
+                    break;
+                }
+                iterations += 1;
+                if iterations == MAX_FIXPOINT_ITERATIONS {
+                    return Err(Error::Pipeline(format!(
+                        "`{}*` did not reach a fixpoint after {MAX_FIXPOINT_ITERATIONS} iterations",
+                        step.pass.name()
+                    )));
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    fn run_step(pass: &mut dyn Pass, cfg: &mut BrilCFG, cache: &mut AnalysisCache, verify_ssa: bool) -> Result<bool> {
+        let mut changed = false;
+        for func in cfg.functions.iter_mut() {
+            let was_ssa = verify_ssa && pass.preserves_ssa() && ssa::verify(func).is_ok();
+            let func_changed = match pass.granularity() {
+                Granularity::Block => {
+                    let mut func_changed = false;
+                    for block in func.blocks.iter_mut() {
+                        func_changed |= pass.run_on_block(block)?;
+                    }
+                    func_changed
+                }
+                Granularity::Function => pass.run_on_function(func, cache)?,
+            };
+            if func_changed {
+                cache.invalidate_function(&func.name);
+                if was_ssa {
+                    ssa::verify(func).map_err(|e| {
+                        Error::Pipeline(format!("`{}` broke the ssa form of @{}: {e}", pass.name(), func.name))
+                    })?;
+                }
+            }
+            changed |= func_changed;
+        }
+        Ok(changed)
+    }
+
+    pub fn cache(&mut self) -> &mut AnalysisCache {
+        &mut self.cache
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Instr, Literal, Opcode};
+
+    // requests an analysis and claims to have changed the function or not
+    struct CountingPass {
+        change: bool,
+    }
+
+    struct Analysis(usize);
+
+    impl Pass for CountingPass {
+        fn name(&self) -> &'static str {
+            "counting"
+        }
+
+        fn granularity(&self) -> Granularity {
+            Granularity::Function
+        }
+
+        fn run_on_function(&mut self, func: &mut FunctionCFG, cache: &mut AnalysisCache) -> Result<bool> {
+            cache.get_or_compute(&func.name, || Analysis(func.blocks.len()));
+            Ok(self.change)
+        }
+    }
+
+    #[test]
+    fn pipeline() {
+        let bril_text = r#"@main{
+        a: int = const 4;
+        b: int = const 2;
+        sum1: int = add a b;
+        sum2: int = add b a;
+        prod: int = mul sum1 sum2;
+        print prod;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        let mut pm = PassManager::from_pipeline("lvn, dce*").unwrap();
+        assert!(pm.run(&mut cfg).unwrap());
+        let bril_txt = cfg.to_text();
+        assert!(!bril_txt.contains("sum2"));
+        // lvn can run again, and a converged pipeline reports no change
+        let mut pm = PassManager::from_pipeline("lvn*,dce*").unwrap();
+        pm.run(&mut cfg).unwrap();
+        assert!(!pm.run(&mut cfg).unwrap());
+        assert_eq!(cfg.to_text(), bril_txt);
+
+        assert!(matches!(PassManager::from_pipeline("lvn,nope"), Err(Error::Pipeline(_))));
+    }
+
+    #[test]
+    fn analysis_invalidation() {
+        let mut cfg = BrilCFG::from_text("@main { print; } @f { ret; }").unwrap();
+        let mut pm = PassManager::new();
+        pm.add(Box::new(CountingPass { change: false }), false);
+        pm.run(&mut cfg).unwrap();
+        assert!(pm.cache().is_cached::<Analysis>("main"));
+        assert_eq!(pm.cache().get_or_compute("f", || Analysis(0)).0, 1);
+
+        // a pass that keeps changing never converges, and drops the cache each time
+        let mut pm = PassManager::new();
+        pm.add(Box::new(CountingPass { change: true }), true);
+        assert!(matches!(pm.run(&mut cfg), Err(Error::Pipeline(_))));
+        assert!(!pm.cache().is_cached::<Analysis>("main"));
+    }
+
+    // claims to preserve ssa, but renames a phi input
+    struct BreakSsa;
+
+    impl Pass for BreakSsa {
+        fn name(&self) -> &'static str {
+            "break-ssa"
+        }
+
+        fn preserves_ssa(&self) -> bool {
+            true
+        }
+
+        fn run_on_block(&mut self, block: &mut Block) -> Result<bool> {
+            let mut changed = false;
+            for instr in block.instrs.iter_mut() {
+                if let Instr::Instruction { op: Opcode::phi, args: Some(args), .. } = instr {
+                    args[0] = "nope".to_string();
+                    changed = true;
+                }
+            }
+            Ok(changed)
+        }
+    }
+
+    #[test]
+    fn verify_ssa() {
+        let bril_text = r#"@main(n: int) {
+  i: int = const 0;
+  one: int = const 1;
+  f: bool = const false;
+.loop:
+  c: bool = lt i n;
+  br c .body .done;
+.body:
+  step: int = sub n one;
+  i: int = add i step;
+  br f .never .loop;
+.never:
+  i: int = add i i;
+  jmp .loop;
+.done:
+  print i;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        let expected = cfg.interpret(&[Literal::Number(4)]).unwrap();
+        let mut pm = PassManager::from_pipeline("to_ssa,lvn,constprop,sccp,licm,dce*,tdce").unwrap();
+        pm.set_verify_ssa(true);
+        pm.run(&mut cfg).unwrap();
+        assert!(!cfg.to_text().contains(".never"));
+        // licm moved `step` in front of the loop
+        let text = cfg.to_text();
+        assert!(text.find("sub n one").unwrap() < text.find(".loop:").unwrap());
+        assert_eq!(cfg.interpret(&[Literal::Number(4)]).unwrap(), expected);
+
+        let mut pm = PassManager::new();
+        pm.set_verify_ssa(true).add(Box::new(BreakSsa), false);
+        match pm.run(&mut cfg) {
+            Err(Error::Pipeline(msg)) => assert!(msg.contains("`break-ssa` broke the ssa form of @main")),
+            result => panic!("{result:?}"),
+        }
+    }
+}
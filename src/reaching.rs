@@ -0,0 +1,243 @@
+// reaching definitions, plus the def-use and use-def chains built from them
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::{self, Display},
+};
+
+use crate::{
+    cfg::{BlockId, FunctionCFG},
+    dataflow::{self, Analysis, DataflowResult, Direction, Site},
+    parser::{Instr, Opcode},
+    ssa::UNDEFINED,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Def {
+    // the `n`th function argument
+    Arg(usize),
+    Instr(Site),
+    // the variable has no value yet when coming from the entry
+    Undefined,
+}
+
+// for every variable, the definitions that may reach a point
+pub type DefMap = BTreeMap<String, BTreeSet<Def>>;
+
+struct Reaching;
+
+impl Analysis for Reaching {
+    type Domain = DefMap;
+
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn boundary(&self, func: &FunctionCFG) -> DefMap {
+        let mut defs = DefMap::new();
+        for block in &func.blocks {
+            for instr in block.instrs() {
+                if let Instr::Instruction { dest, args, .. } = instr {
+                    for var in dest.iter().chain(args.iter().flatten()) {
+                        defs.insert(var.clone(), BTreeSet::from([Def::Undefined]));
+                    }
+                }
+            }
+        }
+        for (i, arg) in func.args.iter().flatten().enumerate() {
+            defs.insert(arg.name.clone(), BTreeSet::from([Def::Arg(i)]));
+        }
+        defs
+    }
+
+    fn top(&self, _func: &FunctionCFG) -> DefMap {
+        DefMap::new()
+    }
+
+    fn meet(&self, into: &mut DefMap, other: &DefMap) {
+        for (var, defs) in other {
+            into.entry(var.clone()).or_default().extend(defs.iter().copied());
+        }
+    }
+
+    fn transfer(&self, site: Site, instr: &Instr, defs: &mut DefMap) {
+        if let Instr::Instruction { dest: Some(dest), .. } = instr {
+            defs.insert(dest.clone(), BTreeSet::from([Def::Instr(site)]));
+        }
+    }
+}
+
+// a read of `var` at `site` that some path from the entry reaches without
+// assigning it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndefinedUse {
+    pub func: String,
+    pub block: String,
+    pub site: Site,
+    pub var: String,
+}
+
+impl Display for UndefinedUse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "warning: @{}: `{}` may be used before it is defined (.{}, instruction {})",
+            self.func, self.var, self.block, self.site.index
+        )
+    }
+}
+
+pub struct ReachingDefinitions {
+    result: DataflowResult<DefMap>,
+    use_def: HashMap<(Site, String), BTreeSet<Def>>,
+    def_use: HashMap<Def, Vec<Site>>,
+    undefined: Vec<UndefinedUse>,
+}
+
+impl ReachingDefinitions {
+    pub fn compute(func: &FunctionCFG) -> Self {
+        let result = dataflow::solve(&Reaching, func);
+        let mut use_def: HashMap<_, BTreeSet<Def>> = HashMap::new();
+        let mut def_use: HashMap<_, Vec<Site>> = HashMap::new();
+        let mut undefined = vec![];
+        let mut warned = HashSet::new();
+
+        let mut reachable = vec![false; func.blocks.len()];
+        for id in func.reverse_postorder() {
+            reachable[id] = true;
+        }
+
+        for (block, b) in func.blocks.iter().enumerate() {
+            let facts = result.instr_facts(&Reaching, func, block);
+            for (index, instr) in b.instrs().iter().enumerate() {
+                let Instr::Instruction { op, args: Some(args), labels, .. } = instr else {
+                    continue;
+                };
+                let site = Site { block, index };
+                // a phi reads each argument at the end of the predecessor it
+                // is labelled with
+                let incoming = if *op == Opcode::phi {
+                    args.iter()
+                        .zip(labels.iter().flatten())
+                        .filter(|(arg, _)| *arg != UNDEFINED)
+                        .filter_map(|(arg, label)| func.block_id(label).map(|pred| (arg, pred, result.block_out(pred))))
+                        .collect::<Vec<_>>()
+                } else {
+                    args.iter().map(|arg| (arg, block, &facts[index])).collect()
+                };
+                for (var, from, reaching) in incoming {
+                    let mut defs = reaching.get(var).cloned().unwrap_or_default();
+                    // nothing reaches an unreachable block from the entry, so
+                    // there is nothing to warn about either
+                    let undefined_here = defs.is_empty() || defs.remove(&Def::Undefined);
+                    if reachable[block] && reachable[from] && undefined_here && warned.insert((site, var.clone())) {
+                        undefined.push(UndefinedUse {
+                            func: func.name.clone(),
+                            block: b.name().to_string(),
+                            site,
+                            var: var.clone(),
+                        });
+                    }
+                    for def in &defs {
+                        let uses = def_use.entry(*def).or_default();
+                        if uses.last() != Some(&site) {
+                            uses.push(site);
+                        }
+                    }
+                    use_def.entry((site, var.clone())).or_default().extend(defs);
+                }
+            }
+        }
+        Self { result, use_def, def_use, undefined }
+    }
+
+    // definitions reaching the start and end of `block`
+    pub fn block_in(&self, block: BlockId) -> &DefMap {
+        self.result.block_in(block)
+    }
+
+    pub fn block_out(&self, block: BlockId) -> &DefMap {
+        self.result.block_out(block)
+    }
+
+    // use-def chain: the definitions of `var` that may reach its read at `site`
+    pub fn defs_of(&self, site: Site, var: &str) -> Option<&BTreeSet<Def>> {
+        self.use_def.get(&(site, var.to_string()))
+    }
+
+    // def-use chain: the instructions that may read the value of `def`
+    pub fn uses_of(&self, def: Def) -> &[Site] {
+        self.def_use.get(&def).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn possibly_undefined(&self) -> &[UndefinedUse] {
+        &self.undefined
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg::BrilCFG;
+
+    #[test]
+    fn reaching_definitions() {
+        let bril_text = r#"@main(n: int) {
+  i: int = const 0;
+.loop:
+  c: bool = lt i n;
+  br c .body .done;
+.body:
+  one: int = const 1;
+  i: int = add i one;
+  jmp .loop;
+.done:
+  print i;
+}"#;
+        let cfg = BrilCFG::from_text(bril_text).unwrap();
+        let main = cfg.function("main").unwrap();
+        let id = |name| main.block_id(name).unwrap();
+        let rd = ReachingDefinitions::compute(main);
+
+        let init = Def::Instr(Site { block: main.entry(), index: 0 });
+        let step = Def::Instr(Site { block: id("body"), index: 1 });
+        assert_eq!(rd.block_in(id("loop"))["i"], BTreeSet::from([init, step]));
+        assert_eq!(rd.block_out(id("body"))["i"], BTreeSet::from([step]));
+
+        let print = Site { block: id("done"), index: 0 };
+        assert_eq!(rd.defs_of(print, "i"), Some(&BTreeSet::from([init, step])));
+        let cmp = Site { block: id("loop"), index: 0 };
+        assert_eq!(rd.defs_of(cmp, "n"), Some(&BTreeSet::from([Def::Arg(0)])));
+        assert_eq!(rd.uses_of(init), &[cmp, Site { block: id("body"), index: 1 }, print]);
+        assert_eq!(rd.uses_of(step), rd.uses_of(init));
+        assert!(rd.possibly_undefined().is_empty());
+    }
+
+    #[test]
+    fn undefined_uses() {
+        let bril_text = r#"@main(c: bool) {
+  br c .then .join;
+.then:
+  x: int = const 1;
+.join:
+  print x;
+  print y;
+  ret;
+.dead:
+  print z;
+}"#;
+        let cfg = BrilCFG::from_text(bril_text).unwrap();
+        let main = cfg.function("main").unwrap();
+        let rd = ReachingDefinitions::compute(main);
+        let join = main.block_id("join").unwrap();
+        let warnings = rd.possibly_undefined();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0].var, "x");
+        assert_eq!(warnings[1].var, "y");
+        assert_eq!(
+            warnings[0].to_string(),
+            "warning: @main: `x` may be used before it is defined (.join, instruction 0)"
+        );
+        // the definition that does reach is still in the use-def chain
+        let x = rd.defs_of(Site { block: join, index: 0 }, "x").unwrap();
+        assert_eq!(x.len(), 1);
+        assert!(rd.defs_of(Site { block: join, index: 1 }, "y").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,423 @@
+// sparse conditional constant propagation (wegman-zadeck) on ssa form
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display},
+};
+
+use crate::{
+    cfg::{BlockId, BrilCFG, FunctionCFG},
+    constprop::{const_value, fold, Value},
+    dataflow::Site,
+    error::{Error, Result},
+    parser::{Instr, Literal, Opcode},
+    pass::{AnalysisCache, Granularity, Pass},
+};
+
+// a `br` whose condition is the same every time it runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConstantBranch {
+    pub block: String,
+    pub cond: bool,
+    pub target: String,
+}
+
+#[derive(Debug, Default)]
+pub struct SccpReport {
+    pub func: String,
+    pub branches: Vec<ConstantBranch>,
+    pub removed_blocks: Vec<String>,
+    // instructions replaced by a `const`
+    pub folded: usize,
+}
+
+impl SccpReport {
+    pub fn changed(&self) -> bool {
+        !self.branches.is_empty() || !self.removed_blocks.is_empty() || self.folded > 0
+    }
+}
+
+impl Display for SccpReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for branch in &self.branches {
+            writeln!(
+                f,
+                "@{}: branch in .{} is always {}, jumps to .{}",
+                self.func, branch.block, branch.cond, branch.target
+            )?;
+        }
+        for block in &self.removed_blocks {
+            writeln!(f, "@{}: removed unreachable block .{block}", self.func)?;
+        }
+        Ok(())
+    }
+}
+
+// a variable missing from `values` has no value yet (top of the lattice)
+struct Solver<'a> {
+    func: &'a FunctionCFG,
+    values: HashMap<String, Value>,
+    uses: HashMap<String, Vec<Site>>,
+    executable: HashSet<(BlockId, BlockId)>,
+    visited: Vec<bool>,
+    flow: Vec<(Option<BlockId>, BlockId)>,
+    ssa: Vec<Site>,
+}
+
+impl<'a> Solver<'a> {
+    fn new(func: &'a FunctionCFG) -> Result<Self> {
+        let mut defined = HashSet::new();
+        let mut uses: HashMap<_, Vec<Site>> = HashMap::new();
+        let mut values = HashMap::new();
+        for arg in func.args.iter().flatten() {
+            defined.insert(arg.name.clone());
+            values.insert(arg.name.clone(), Value::Varying);
+        }
+        for (block, b) in func.blocks.iter().enumerate() {
+            for (index, instr) in b.instrs().iter().enumerate() {
+                let Instr::Instruction { dest, args, .. } = instr else {
+                    return Err(Error::MalformedCfg(format!("unexpected label in block {}", b.name())));
+                };
+                if let Some(dest) = dest {
+                    if !defined.insert(dest.clone()) {
+                        return Err(Error::MalformedCfg(format!(
+                            "sccp needs ssa form, `{dest}` is assigned more than once in @{}",
+                            func.name
+                        )));
+                    }
+                }
+                for arg in args.iter().flatten() {
+                    uses.entry(arg.clone()).or_default().push(Site { block, index });
+                }
+            }
+        }
+        Ok(Self {
+            func,
+            values,
+            uses,
+            executable: HashSet::new(),
+            visited: vec![false; func.blocks.len()],
+            flow: vec![(None, func.entry())],
+            ssa: vec![],
+        })
+    }
+
+    fn solve(&mut self) {
+        loop {
+            while !self.flow.is_empty() || !self.ssa.is_empty() {
+                if let Some((from, to)) = self.flow.pop() {
+                    if let Some(from) = from {
+                        if !self.executable.insert((from, to)) {
+                            continue;
+                        }
+                    }
+                    let only_phis = self.visited[to];
+                    self.visited[to] = true;
+                    let instrs = self.func.blocks[to].instrs();
+                    for (index, instr) in instrs.iter().enumerate() {
+                        if only_phis && !matches!(instr, Instr::Instruction { op: Opcode::phi, .. }) {
+                            continue;
+                        }
+                        self.visit(Site { block: to, index });
+                    }
+                    if instrs.is_empty() {
+                        self.flow.extend(self.func.blocks[to].succs().iter().map(|succ| (Some(to), *succ)));
+                    }
+                }
+                if let Some(site) = self.ssa.pop() {
+                    if self.visited[site.block] {
+                        self.visit(site);
+                    }
+                }
+            }
+            // a branch on a variable that never gets a value is undefined
+            // behaviour. assume it can go both ways and keep going
+            for (id, block) in self.func.blocks.iter().enumerate() {
+                if let (true, Some(Instr::Instruction { op: Opcode::br, args: Some(args), .. })) =
+                    (self.visited[id], block.instrs().last())
+                {
+                    if args.first().is_some_and(|cond| !self.values.contains_key(cond)) {
+                        self.flow.extend(block.succs().iter().map(|succ| (Some(id), *succ)));
+                    }
+                }
+            }
+            self.flow.retain(|(from, to)| !self.executable.contains(&(from.unwrap(), *to)));
+            if self.flow.is_empty() {
+                return;
+            }
+        }
+    }
+
+    fn visit(&mut self, site: Site) {
+        let block = &self.func.blocks[site.block];
+        let instr = &block.instrs()[site.index];
+        let Instr::Instruction { op, dest, args, labels, .. } = instr else {
+            return;
+        };
+        if let Some(dest) = dest {
+            if let Some(value) = self.evaluate(site.block, instr) {
+                let old = self.values.get(dest);
+                // only ever move down the lattice
+                let value = match old {
+                    Some(old) if *old != value => Value::Varying,
+                    _ => value,
+                };
+                if old != Some(&value) {
+                    self.values.insert(dest.clone(), value);
+                    self.ssa.extend(self.uses.get(dest).into_iter().flatten().copied());
+                }
+            }
+        }
+
+        let from = Some(site.block);
+        match op {
+            Opcode::br => match args.iter().flatten().next().and_then(|cond| self.values.get(cond)) {
+                Some(Value::Const(Literal::Bool(cond))) => {
+                    let target = labels.iter().flatten().nth(if *cond { 0 } else { 1 });
+                    if let Some(target) = target.and_then(|label| self.func.block_id(label)) {
+                        self.flow.push((from, target));
+                    }
+                }
+                Some(_) => self.flow.extend(block.succs().iter().map(|succ| (from, *succ))),
+                None => {}
+            },
+            Opcode::ret => {}
+            // jumps and falling through to the next block
+            _ if site.index + 1 == block.instrs().len() => {
+                self.flow.extend(block.succs().iter().map(|succ| (from, *succ)));
+            }
+            _ => {}
+        }
+    }
+
+    // the value of the dest of `instr`, None while it is still unknown
+    fn evaluate(&self, block: BlockId, instr: &Instr) -> Option<Value> {
+        let Instr::Instruction { op, typ, args, labels, value, .. } = instr else {
+            return None;
+        };
+        let args = args.iter().flatten();
+        match op {
+            Opcode::cst => Some(value.as_ref().map_or(Value::Varying, |value| Value::Const(const_value(typ, value)))),
+            // meet of the incoming values along executable edges only
+            Opcode::phi => {
+                let mut result = None;
+                for (arg, label) in args.zip(labels.iter().flatten()) {
+                    let executable = self
+                        .func
+                        .block_id(label)
+                        .is_some_and(|pred| self.executable.contains(&(pred, block)));
+                    match (executable.then(|| self.values.get(arg)).flatten(), &result) {
+                        (None, _) => {}
+                        (Some(value), None) => result = Some(value.clone()),
+                        (Some(value), Some(other)) if value != other => return Some(Value::Varying),
+                        (Some(_), Some(_)) => {}
+                    }
+                }
+                result
+            }
+            _ if op.is_pure() => {
+                let mut operands = vec![];
+                let mut unknown = false;
+                for arg in args {
+                    match self.values.get(arg) {
+                        Some(Value::Const(value)) => operands.push(value.clone()),
+                        Some(Value::Varying) => return Some(Value::Varying),
+                        None => unknown = true,
+                    }
+                }
+                if unknown {
+                    return None;
+                }
+                Some(fold(op, &operands).map_or(Value::Varying, Value::Const))
+            }
+            _ => Some(Value::Varying),
+        }
+    }
+}
+
+pub struct Sccp;
+
+impl Pass for Sccp {
+    fn name(&self) -> &'static str {
+        "sccp"
+    }
+
+    fn preserves_ssa(&self) -> bool {
+        true
+    }
+
+    fn granularity(&self) -> Granularity {
+        Granularity::Function
+    }
+
+    fn run_on_function(&mut self, func: &mut FunctionCFG, _cache: &mut AnalysisCache) -> Result<bool> {
+        Ok(func.sccp()?.changed())
+    }
+}
+
+impl BrilCFG {
+    pub fn sccp(&mut self) -> Result<Vec<SccpReport>> {
+        self.functions.iter_mut().map(FunctionCFG::sccp).collect()
+    }
+}
+
+impl FunctionCFG {
+    // fold constants, turn branches on constants into jumps and drop the
+    // blocks that can never run, along with their phi inputs
+    pub fn sccp(&mut self) -> Result<SccpReport> {
+        let mut solver = Solver::new(self)?;
+        solver.solve();
+        let Solver { values, executable, visited, .. } = solver;
+        let mut report = SccpReport {
+            func: self.name.clone(),
+            ..Default::default()
+        };
+
+        let names = self.blocks.iter().map(|block| block.name().to_string()).collect::<Vec<_>>();
+        for (id, block) in self.blocks.iter_mut().enumerate() {
+            if !visited[id] {
+                continue;
+            }
+            for instr in block.instrs.iter_mut() {
+                match instr {
+                    Instr::Instruction { op: Opcode::phi, args: Some(args), labels: Some(labels), .. } => {
+                        let incoming = args
+                            .iter()
+                            .zip(labels.iter())
+                            .filter(|(_, label)| {
+                                names
+                                    .iter()
+                                    .position(|name| name == *label)
+                                    .is_some_and(|pred| executable.contains(&(pred, id)))
+                            })
+                            .map(|(arg, label)| (arg.clone(), label.clone()))
+                            .collect::<Vec<_>>();
+                        *args = incoming.iter().map(|(arg, _)| arg.clone()).collect();
+                        *labels = incoming.into_iter().map(|(_, label)| label).collect();
+                    }
+                    Instr::Instruction { op: Opcode::br, args: Some(args), labels: Some(labels), .. } => {
+                        if let (Some(Value::Const(Literal::Bool(cond))), [on_true, on_false]) =
+                            (args.first().and_then(|cond| values.get(cond)), labels.as_slice())
+                        {
+                            let target = if *cond { on_true } else { on_false }.clone();
+                            report.branches.push(ConstantBranch {
+                                block: names[id].clone(),
+                                cond: *cond,
+                                target: target.clone(),
+                            });
+                            *instr = Instr::Instruction {
+                                op: Opcode::jmp,
+                                dest: None,
+                                typ: None,
+                                args: None,
+                                funcs: None,
+                                labels: Some(vec![target]),
+                                value: None,
+                            };
+                        }
+                    }
+                    _ => {}
+                }
+                if let Instr::Instruction { op, dest: Some(dest), typ: Some(typ), .. } = instr {
+                    if let (false, Some(Value::Const(value))) = (*op == Opcode::cst, values.get(dest)) {
+                        *instr = Instr::new_const_instr(dest, value.clone(), typ.clone());
+                        report.folded += 1;
+                    }
+                }
+            }
+        }
+
+        report.removed_blocks = (0..self.blocks.len()).filter(|id| !visited[*id]).map(|id| names[id].clone()).collect();
+        let mut visited = visited.into_iter();
+        self.blocks.retain(|_| visited.next().unwrap());
+        self.resolve_cfg()?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sccp() {
+        // x stays 1 around the loop because the only redefinition is in a
+        // block that never runs, which plain constant propagation misses
+        let bril_text = r#"@main(n: int) {
+.entry:
+  i0: int = const 0;
+  x0: int = const 1;
+  one: int = const 1;
+.loop:
+  x: int = phi x0 x1 .entry .latch;
+  i: int = phi i0 i1 .entry .latch;
+  c: bool = lt i n;
+  br c .body .done;
+.body:
+  t: bool = eq x one;
+  br t .same .diff;
+.diff:
+  x2: int = const 5;
+  jmp .latch;
+.same:
+  jmp .latch;
+.latch:
+  x1: int = phi x x2 .same .diff;
+  i1: int = add i one;
+  jmp .loop;
+.done:
+  print x;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        let reports = cfg.sccp().unwrap();
+        let bril_txt = cfg.to_text();
+        println!("bril_txt: {bril_txt}");
+        println!("{}", reports[0]);
+        assert!(bril_txt.contains("x: int = const 1;"));
+        assert!(bril_txt.contains("x1: int = const 1;"));
+        assert!(bril_txt.contains("t: bool = const true;"));
+        assert!(bril_txt.contains("jmp .same;"));
+        assert!(bril_txt.contains("i: int = phi i0 i1 .entry .latch;"));
+        assert!(bril_txt.contains("br c .body .done;"));
+        assert!(!bril_txt.contains(".diff"));
+        assert!(!bril_txt.contains("x2"));
+
+        let report = &reports[0];
+        assert_eq!(
+            report.branches,
+            vec![ConstantBranch {
+                block: "body".to_string(),
+                cond: true,
+                target: "same".to_string(),
+            }]
+        );
+        assert_eq!(report.removed_blocks, vec!["diff".to_string()]);
+        assert!(report.to_string().contains("@main: branch in .body is always true, jumps to .same"));
+
+        let main = cfg.function("main").unwrap();
+        let latch = main.block_id("latch").unwrap();
+        assert_eq!(main.blocks[latch].preds(), &[main.block_id("same").unwrap()]);
+        // nothing left to do
+        assert!(!cfg.sccp().unwrap()[0].changed());
+    }
+
+    #[test]
+    fn sccp_needs_ssa() {
+        let mut cfg = BrilCFG::from_text("@main { a: int = const 1; a: int = const 2; print a; }").unwrap();
+        assert!(matches!(cfg.sccp(), Err(Error::MalformedCfg(_))));
+    }
+
+    #[test]
+    fn sccp_undefined_condition() {
+        let bril_text = r#"@main {
+  br c .a .b;
+.a:
+  print c;
+.b:
+  ret;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        let report = cfg.sccp().unwrap().remove(0);
+        assert!(!report.changed());
+        assert!(cfg.to_text().contains("br c .a .b;"));
+    }
+}
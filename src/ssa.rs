@@ -0,0 +1,833 @@
+// conversion into ssa form: phis at the iterated dominance frontiers of the
+// definitions, then renaming along the dominator tree
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::{
+    cfg::{Block, BlockId, BrilCFG, FunctionCFG},
+    dataflow::Site,
+    dominators::Dominators,
+    error::{Error, Result},
+    liveness::Liveness,
+    parser::{Instr, Opcode, Type},
+    pass::{AnalysisCache, Granularity, Pass},
+    reaching::ReachingDefinitions,
+};
+
+// phi argument for a predecessor on which the variable has no value
+pub const UNDEFINED: &str = "__undefined";
+
+// every variable is assigned at most once, and function arguments never
+pub fn is_ssa(func: &FunctionCFG) -> bool {
+    let mut defined = func.args.iter().flatten().map(|arg| arg.name.as_str()).collect::<HashSet<_>>();
+    func.blocks.iter().flat_map(|block| block.instrs()).all(|instr| match instr {
+        Instr::Instruction { dest: Some(dest), .. } => defined.insert(dest.as_str()),
+        _ => true,
+    })
+}
+
+// the invariants of ssa form: every variable has a single definition that
+// dominates its uses, phis come first in their block and have exactly one
+// input per predecessor. a phi input is used at the end of its predecessor
+pub fn verify(func: &FunctionCFG) -> Result<()> {
+    let fail = |msg: String| Err(Error::InvalidSsa(format!("@{}: {msg}", func.name)));
+
+    // None for the function arguments, defined before the entry
+    let mut defs = HashMap::new();
+    for arg in func.args.iter().flatten() {
+        if defs.insert(arg.name.as_str(), None).is_some() {
+            return fail(format!("argument `{}` is declared twice", arg.name));
+        }
+    }
+    for (block, b) in func.blocks.iter().enumerate() {
+        for (index, instr) in b.instrs().iter().enumerate() {
+            match instr {
+                Instr::Instruction { dest: Some(dest), .. } => {
+                    if defs.insert(dest.as_str(), Some(Site { block, index })).is_some() {
+                        return fail(format!("`{dest}` is defined more than once"));
+                    }
+                }
+                Instr::Instruction { .. } => {}
+                Instr::Label { label } => return fail(format!("label `{label}` inside block {}", b.name())),
+            }
+        }
+    }
+
+    let dom = Dominators::compute(func);
+    // whether `var` is defined before `index` in `block`, or at its end
+    let available = |var: &str, block: BlockId, index: Option<usize>| match defs.get(var) {
+        None => false,
+        Some(None) => true,
+        Some(Some(def)) if def.block == block => index.is_none_or(|index| def.index < index),
+        Some(Some(def)) => dom.strictly_dominates(def.block, block),
+    };
+
+    for (block, b) in func.blocks.iter().enumerate() {
+        // nothing runs in unreachable blocks, and nothing dominates them
+        if !dom.is_reachable(block) {
+            continue;
+        }
+        let mut phis_done = false;
+        for (index, instr) in b.instrs().iter().enumerate() {
+            let Instr::Instruction { op, args, labels, .. } = instr else {
+                continue;
+            };
+            let args = args.as_deref().unwrap_or_default();
+            if *op != Opcode::phi {
+                phis_done = true;
+                for arg in args {
+                    if !defs.contains_key(arg.as_str()) {
+                        return fail(format!("`{arg}` is used in .{} but never defined", b.name()));
+                    }
+                    if !available(arg, block, Some(index)) {
+                        return fail(format!("the definition of `{arg}` does not dominate its use in .{}", b.name()));
+                    }
+                }
+                continue;
+            }
+
+            if phis_done {
+                return fail(format!("phi after other instructions in .{}: {instr}", b.name()));
+            }
+            let labels = labels.as_deref().unwrap_or_default();
+            if labels.len() != args.len() {
+                return fail(format!("phi with {} args and {} labels: {instr}", args.len(), labels.len()));
+            }
+            let mut preds = vec![];
+            for label in labels {
+                let Some(pred) = func.block_id(label) else {
+                    return fail(format!("phi label `{label}` is not a block: {instr}"));
+                };
+                preds.push(pred);
+            }
+            preds.sort();
+            let mut expected = b.preds().to_vec();
+            expected.sort();
+            if preds != expected {
+                let names = expected.iter().map(|pred| format!(".{}", func.blocks[*pred].name())).collect::<Vec<_>>();
+                return fail(format!("phi labels don't match the predecessors [{}]: {instr}", names.join(", ")));
+            }
+            for (arg, pred) in args.iter().zip(labels.iter().filter_map(|label| func.block_id(label))) {
+                if arg == UNDEFINED || !dom.is_reachable(pred) {
+                    continue;
+                }
+                if !defs.contains_key(arg.as_str()) {
+                    return fail(format!("`{arg}` is used in .{} but never defined", b.name()));
+                }
+                if !available(arg, pred, None) {
+                    return fail(format!(
+                        "the definition of `{arg}` does not reach the end of .{}: {instr}",
+                        func.blocks[pred].name()
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn is_phi(instr: &Instr) -> bool {
+    matches!(instr, Instr::Instruction { op: Opcode::phi, .. })
+}
+
+// the arguments and all variables assigned in `func`
+pub(crate) fn var_names(func: &FunctionCFG) -> HashSet<String> {
+    let mut names = func.args.iter().flatten().map(|arg| arg.name.clone()).collect::<HashSet<_>>();
+    for instr in func.blocks.iter().flat_map(|block| block.instrs()) {
+        if let Instr::Instruction { dest: Some(dest), .. } = instr {
+            names.insert(dest.clone());
+        }
+    }
+    names
+}
+
+// a name based on `var` that is not in `names` yet
+pub(crate) fn fresh_var(names: &mut HashSet<String>, var: &str) -> String {
+    (0..)
+        .map(|n| format!("{var}.tmp{n}"))
+        .find(|name| names.insert(name.clone()))
+        .unwrap()
+}
+
+// order the parallel copies `dest <- src` so that no copy overwrites a value
+// another one still has to read, breaking cycles like a swap with a temporary
+fn sequentialize(mut copies: Vec<(String, String, Type)>, names: &mut HashSet<String>) -> Vec<Instr> {
+    copies.retain(|(dest, src, _)| dest != src);
+    let mut instrs = vec![];
+    while !copies.is_empty() {
+        let ready = copies.iter().position(|(dest, _, _)| copies.iter().all(|(_, src, _)| src != dest));
+        if let Some(i) = ready {
+            let (dest, src, typ) = copies.remove(i);
+            instrs.push(Instr::new_id_instr(&dest, &src, typ));
+        } else {
+            // every pending dest is read by another copy, so they form
+            // cycles. saving one dest frees up its cycle
+            let (dest, _, typ) = copies[0].clone();
+            let tmp = fresh_var(names, &dest);
+            instrs.push(Instr::new_id_instr(&tmp, &dest, typ));
+            for (_, src, _) in copies.iter_mut().filter(|(_, src, _)| *src == dest) {
+                *src = tmp.clone();
+            }
+        }
+    }
+    instrs
+}
+
+struct Renamer {
+    // the current name of every original variable, innermost last
+    stacks: HashMap<String, Vec<String>>,
+    counters: HashMap<String, usize>,
+    // all names in use, so `x.1` never collides with a variable of the program
+    names: HashSet<String>,
+    // the variable each phi at the start of a block was placed for
+    phis: Vec<Vec<String>>,
+}
+
+impl Renamer {
+    fn fresh(&mut self, var: &str) -> String {
+        let counter = self.counters.entry(var.to_string()).or_default();
+        loop {
+            let name = format!("{var}.{counter}");
+            *counter += 1;
+            if self.names.insert(name.clone()) {
+                return name;
+            }
+        }
+    }
+
+    fn current(&self, var: &str) -> Option<&String> {
+        self.stacks.get(var).and_then(|stack| stack.last())
+    }
+
+    // walks the dominator tree with a stack of its own, a long chain of
+    // blocks would overflow the call stack. each block is visited once to
+    // rename it, and once more to drop its names when its subtree is done
+    fn rename(&mut self, func: &mut FunctionCFG, dom: &Dominators, entry: BlockId) {
+        let mut frames = vec![(entry, None)];
+        while let Some((block, pushed)) = frames.pop() {
+            if let Some(pushed) = pushed {
+                for var in pushed {
+                    self.stacks.get_mut(&var).and_then(|stack| stack.pop());
+                }
+                continue;
+            }
+            let pushed = self.rename_block(func, block);
+            frames.push((block, Some(pushed)));
+            frames.extend(dom.children(block).iter().rev().map(|child| (*child, None)));
+        }
+    }
+
+    // returns the variables it pushed a name for
+    fn rename_block(&mut self, func: &mut FunctionCFG, block: BlockId) -> Vec<String> {
+        let mut pushed = vec![];
+        let num_phis = self.phis[block].len();
+        for (index, instr) in func.blocks[block].instrs.iter_mut().enumerate() {
+            let Instr::Instruction { dest, args, .. } = instr else {
+                continue;
+            };
+            // phis read their arguments at the end of the predecessors
+            if index >= num_phis {
+                for arg in args.iter_mut().flatten() {
+                    if let Some(name) = self.current(arg) {
+                        *arg = name.clone();
+                    }
+                }
+            }
+            if let Some(dest) = dest {
+                let var = if index < num_phis { self.phis[block][index].clone() } else { dest.clone() };
+                let name = self.fresh(&var);
+                self.stacks.entry(var.clone()).or_default().push(name.clone());
+                pushed.push(var);
+                *dest = name;
+            }
+        }
+
+        let name = func.blocks[block].name.clone();
+        for succ in func.blocks[block].succs().to_vec() {
+            for (index, var) in self.phis[succ].iter().enumerate() {
+                let arg = self.current(var).cloned().unwrap_or_else(|| UNDEFINED.to_string());
+                if let Instr::Instruction { args: Some(args), labels: Some(labels), .. } =
+                    &mut func.blocks[succ].instrs[index]
+                {
+                    args.push(arg);
+                    labels.push(name.clone());
+                }
+            }
+        }
+        pushed
+    }
+}
+
+pub struct ToSsa;
+
+impl Pass for ToSsa {
+    fn name(&self) -> &'static str {
+        "to_ssa"
+    }
+
+    fn granularity(&self) -> Granularity {
+        Granularity::Function
+    }
+
+    fn run_on_function(&mut self, func: &mut FunctionCFG, _cache: &mut AnalysisCache) -> Result<bool> {
+        func.to_ssa()
+    }
+}
+
+pub struct FromSsa;
+
+impl Pass for FromSsa {
+    fn name(&self) -> &'static str {
+        "from_ssa"
+    }
+
+    fn granularity(&self) -> Granularity {
+        Granularity::Function
+    }
+
+    fn run_on_function(&mut self, func: &mut FunctionCFG, _cache: &mut AnalysisCache) -> Result<bool> {
+        func.from_ssa()
+    }
+}
+
+impl BrilCFG {
+    pub fn to_ssa(&mut self) -> Result<()> {
+        for func in self.functions.iter_mut() {
+            func.to_ssa()?;
+        }
+        Ok(())
+    }
+
+    pub fn from_ssa(&mut self) -> Result<()> {
+        for func in self.functions.iter_mut() {
+            func.from_ssa()?;
+        }
+        Ok(())
+    }
+}
+
+impl FunctionCFG {
+    // returns false if the function was in ssa form already
+    pub fn to_ssa(&mut self) -> Result<bool> {
+        if self.blocks.iter().flat_map(|block| block.instrs()).any(is_phi) {
+            if is_ssa(self) {
+                return Ok(false);
+            }
+            return Err(Error::MalformedCfg(format!(
+                "@{} has phis but is not in ssa form",
+                self.name
+            )));
+        }
+        // single assignments are not enough, every use also needs to see a
+        // definition on each path
+        if is_ssa(self) && ReachingDefinitions::compute(self).possibly_undefined().is_empty() {
+            return Ok(false);
+        }
+
+        // blocks that never run would keep their old names
+        let mut reachable = vec![false; self.blocks.len()];
+        for id in self.reverse_postorder() {
+            reachable[id] = true;
+        }
+        let mut reachable = reachable.into_iter();
+        self.blocks.retain(|_| reachable.next().unwrap());
+        self.resolve_cfg()?;
+        self.split_entry()?;
+
+        let mut types = HashMap::new();
+        let mut defs: HashMap<String, BTreeSet<BlockId>> = HashMap::new();
+        let mut names = HashSet::new();
+        for arg in self.args.iter().flatten() {
+            types.insert(arg.name.clone(), arg.typ.clone());
+            defs.entry(arg.name.clone()).or_default().insert(self.entry());
+        }
+        for (id, block) in self.blocks.iter().enumerate() {
+            for instr in block.instrs() {
+                if let Instr::Instruction { dest, typ, args, .. } = instr {
+                    if let (Some(dest), Some(typ)) = (dest, typ) {
+                        types.entry(dest.clone()).or_insert_with(|| typ.clone());
+                    }
+                    if let Some(dest) = dest {
+                        defs.entry(dest.clone()).or_default().insert(id);
+                    }
+                    names.extend(dest.iter().chain(args.iter().flatten()).cloned());
+                }
+            }
+        }
+
+        let dom = Dominators::compute(self);
+        let frontiers = dom.frontiers(self);
+        let live = Liveness::compute(self);
+        let mut phis = vec![vec![]; self.blocks.len()];
+        let mut vars = defs.keys().cloned().collect::<Vec<_>>();
+        vars.sort();
+        for var in &vars {
+            let mut placed = HashSet::new();
+            let mut worklist = defs[var].iter().copied().collect::<Vec<_>>();
+            while let Some(block) = worklist.pop() {
+                for frontier in &frontiers[block] {
+                    // pruned ssa, no phis for dead variables
+                    if !live.block_in(*frontier).contains(var) || !placed.insert(*frontier) {
+                        continue;
+                    }
+                    phis[*frontier].push(var.clone());
+                    if !defs[var].contains(frontier) {
+                        worklist.push(*frontier);
+                    }
+                }
+            }
+        }
+
+        for (block, vars) in self.blocks.iter_mut().zip(&phis) {
+            let mut instrs = Vec::with_capacity(vars.len() + block.instrs.len());
+            for var in vars {
+                let typ = types
+                    .get(var)
+                    .ok_or_else(|| Error::MalformedCfg(format!("no type for `{var}` in @{}", self.name)))?;
+                instrs.push(Instr::Instruction {
+                    op: Opcode::phi,
+                    dest: Some(var.clone()),
+                    typ: Some(typ.clone()),
+                    args: Some(vec![]),
+                    funcs: None,
+                    labels: Some(vec![]),
+                    value: None,
+                });
+            }
+            instrs.append(&mut block.instrs);
+            block.instrs = instrs;
+        }
+
+        let mut renamer = Renamer {
+            stacks: self
+                .args
+                .iter()
+                .flatten()
+                .map(|arg| (arg.name.clone(), vec![arg.name.clone()]))
+                .collect(),
+            counters: HashMap::new(),
+            names,
+            phis,
+        };
+        renamer.rename(self, &dom, self.entry());
+        Ok(true)
+    }
+
+    // replace the phis with copies at the end of the predecessors. returns
+    // false if there were no phis
+    pub fn from_ssa(&mut self) -> Result<bool> {
+        let has_phis = |block: &Block| block.instrs().iter().any(is_phi);
+        if !self.blocks.iter().any(has_phis) {
+            return Ok(false);
+        }
+
+        // copies at the end of a block with several successors would run on
+        // all its outgoing edges, so those edges get a block of their own
+        for to in 0..self.blocks.len() {
+            if !has_phis(&self.blocks[to]) {
+                continue;
+            }
+            for from in self.blocks[to].preds().to_vec() {
+                if self.blocks[from].succs().len() < 2 {
+                    continue;
+                }
+                let target = self.blocks[to].name.clone();
+                let split = self.add_block(
+                    "split",
+                    vec![Instr::Instruction {
+                        op: Opcode::jmp,
+                        dest: None,
+                        typ: None,
+                        args: None,
+                        funcs: None,
+                        labels: Some(vec![target]),
+                        value: None,
+                    }],
+                );
+                self.redirect_edge(from, to, split);
+                let (old, new) = (self.blocks[from].name.clone(), self.blocks[split].name.clone());
+                for instr in self.blocks[to].instrs.iter_mut().filter(|instr| is_phi(instr)) {
+                    if let Instr::Instruction { labels: Some(labels), .. } = instr {
+                        labels.iter_mut().filter(|label| **label == old).for_each(|label| *label = new.clone());
+                    }
+                }
+            }
+        }
+        self.resolve_cfg()?;
+
+        let mut names = HashSet::new();
+        names.extend(self.args.iter().flatten().map(|arg| arg.name.clone()));
+        let mut copies = vec![vec![]; self.blocks.len()];
+        let ids = self.blocks.iter().enumerate().map(|(id, block)| (block.name.clone(), id)).collect::<HashMap<_, _>>();
+        for block in self.blocks.iter_mut() {
+            for instr in &block.instrs {
+                if let Instr::Instruction { dest, args, .. } = instr {
+                    names.extend(dest.iter().chain(args.iter().flatten()).cloned());
+                }
+            }
+            for instr in block.instrs.extract_if(.., |instr| is_phi(instr)) {
+                let Instr::Instruction { dest: Some(dest), typ: Some(typ), args, labels, .. } = &instr else {
+                    return Err(Error::MalformedCfg(format!("phi without dest or type: {instr}")));
+                };
+                for (arg, label) in args.iter().flatten().zip(labels.iter().flatten()) {
+                    // nothing to copy, the dest just stays undefined
+                    if arg == UNDEFINED {
+                        continue;
+                    }
+                    let pred = ids.get(label).copied().ok_or_else(|| {
+                        Error::MalformedCfg(format!("phi label `{label}` is not a block of @{}", self.name))
+                    })?;
+                    copies[pred].push((dest.clone(), arg.clone(), typ.clone()));
+                }
+            }
+        }
+
+        for (block, copies) in self.blocks.iter_mut().zip(copies) {
+            let copies = sequentialize(copies, &mut names);
+            let terminated = matches!(
+                block.instrs.last(),
+                Some(Instr::Instruction { op: Opcode::jmp | Opcode::br | Opcode::ret, .. })
+            );
+            let at = block.instrs.len() - usize::from(terminated);
+            block.instrs.splice(at..at, copies);
+        }
+        Ok(true)
+    }
+
+    // drop the phi inputs of `block` for the edge from `pred`, once a pass
+    // removed that edge
+    pub fn remove_phi_inputs(&mut self, block: BlockId, pred: BlockId) {
+        let pred = self.blocks[pred].name.clone();
+        for instr in self.blocks[block].instrs.iter_mut() {
+            if let Instr::Instruction { op: Opcode::phi, args: Some(args), labels: Some(labels), .. } = instr {
+                let mut keep = labels.iter().map(|label| *label != pred).collect::<Vec<_>>().into_iter();
+                args.retain(|_| keep.next().unwrap_or(true));
+                labels.retain(|label| *label != pred);
+            }
+        }
+    }
+
+    // the entry block is never printed with a label, so a phi can't name it.
+    // give it a label and put a new empty entry in front of it when one of
+    // its successors may get phis
+    fn split_entry(&mut self) -> Result<()> {
+        let entry = self.entry();
+        let needed = self.blocks[entry].succs().iter().any(|succ| self.blocks[*succ].preds().len() > 1);
+        if !needed {
+            return Ok(());
+        }
+        let name = self.fresh_block_name("entry");
+        let old = std::mem::replace(&mut self.blocks[entry].name, name);
+        self.blocks.insert(entry, Block::new(old, vec![]));
+        self.resolve_cfg()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dominators::tests::{random_function, Rng},
+        parser::Literal,
+    };
+
+    #[test]
+    fn to_ssa_loop() {
+        let bril_text = r#"@main(n: int) {
+  i: int = const 0;
+  one: int = const 1;
+.loop:
+  c: bool = lt i n;
+  br c .body .done;
+.body:
+  i: int = add i one;
+  n: int = id n;
+  jmp .loop;
+.done:
+  print i;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        assert!(!is_ssa(cfg.function("main").unwrap()));
+        cfg.to_ssa().unwrap();
+        let bril_txt = cfg.to_text();
+        println!("bril_txt: {bril_txt}");
+        let main = cfg.function("main").unwrap();
+        assert!(is_ssa(main));
+        assert!(bril_txt.contains(".entry0:\n  i.0: int = const 0;"));
+        assert!(bril_txt.contains("i.1: int = phi i.0 i.2 .entry0 .body;"));
+        assert!(bril_txt.contains("n.0: int = phi n n.1 .entry0 .body;"));
+        assert!(bril_txt.contains("c.0: bool = lt i.1 n.0;"));
+        assert!(bril_txt.contains("i.2: int = add i.1 one.0;"));
+        assert!(bril_txt.contains("print i.1;"));
+        // `one` and `c` don't need a phi
+        assert!(!bril_txt.contains("one.1"));
+        assert!(!bril_txt.contains("c.1"));
+
+        // the output parses back, and converting it again does nothing
+        let mut cfg = BrilCFG::from_text(&bril_txt).unwrap();
+        assert!(!cfg.functions[0].to_ssa().unwrap());
+    }
+
+    #[test]
+    fn to_ssa_undefined() {
+        let bril_text = r#"@main(c: bool) {
+  br c .then .join;
+.then:
+  x: int = const 1;
+.join:
+  print x;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        cfg.to_ssa().unwrap();
+        let bril_txt = cfg.to_text();
+        println!("bril_txt: {bril_txt}");
+        assert!(bril_txt.contains("x.1: int = phi __undefined x.0 .entry0 .then;"));
+        assert!(bril_txt.contains("print x.1;"));
+        assert!(!cfg.functions[0].to_ssa().unwrap());
+    }
+
+    #[test]
+    fn to_ssa_long_chain() {
+        // as deep a dominator tree as there are blocks
+        let mut bril_text = "@main {\n  x: int = const 0;\n  one: int = const 1;\n".to_string();
+        for k in 0..20000 {
+            bril_text.push_str(&format!(".b{k}:\n  x: int = add x one;\n"));
+        }
+        bril_text.push_str("  print x;\n}");
+        let mut cfg = BrilCFG::from_text(&bril_text).unwrap();
+        cfg.to_ssa().unwrap();
+        verify(&cfg.functions[0]).unwrap();
+        assert_eq!(cfg.interpret(&[]).unwrap(), "20000\n");
+    }
+
+    #[test]
+    fn to_ssa_random() {
+        let mut rng = Rng::new(7);
+        for _ in 0..100 {
+            let n = rng.below(8) + 1;
+            let mut text = String::new();
+            for (k, line) in random_function(&mut rng, n).lines().enumerate() {
+                let line = match (line, k % 3) {
+                    // a program can't read a variable that is never defined
+                    ("@main(c: bool) {", _) => "@main(c: bool) {\n  x: int = const 0;".to_string(),
+                    ("  print c;", 0) => format!("  x: int = const {k};"),
+                    ("  print c;", 1) => "  x: int = add x x;".to_string(),
+                    (line, _) => line.to_string(),
+                };
+                text.push_str(&line);
+                text.push('\n');
+            }
+            let mut cfg = BrilCFG::from_text(&text).unwrap();
+            cfg.to_ssa().unwrap();
+            let func = &cfg.functions[0];
+            assert!(is_ssa(func), "{text}\n{}", cfg.to_text());
+            verify(func).unwrap();
+            // every phi has one input per predecessor
+            for block in &func.blocks {
+                for instr in block.instrs().iter().filter(|instr| is_phi(instr)) {
+                    let Instr::Instruction { args: Some(args), labels: Some(labels), .. } = instr else {
+                        panic!("phi without args: {instr}");
+                    };
+                    assert_eq!(args.len(), block.preds().len());
+                    assert_eq!(labels.len(), block.preds().len());
+                }
+            }
+            BrilCFG::from_text(&cfg.to_text()).unwrap();
+        }
+    }
+
+    fn no_phis(cfg: &BrilCFG) -> bool {
+        cfg.blocks().flat_map(|block| block.instrs()).all(|instr| !is_phi(instr))
+    }
+
+    #[test]
+    fn from_ssa_swap() {
+        // the phis swap x and y on every iteration, copied one after the
+        // other the second copy would read the new value of the first
+        let bril_text = r#"@main {
+.top:
+  a: int = const 1;
+  b: int = const 2;
+  i0: int = const 0;
+  one: int = const 1;
+  three: int = const 3;
+.loop:
+  x: int = phi a y .top .loop;
+  y: int = phi b x .top .loop;
+  i: int = phi i0 i1 .top .loop;
+  i1: int = add i one;
+  print x y;
+  c: bool = lt i1 three;
+  br c .loop .done;
+.done:
+  print x y;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        let expected = cfg.interpret(&[]).unwrap();
+        assert_eq!(expected, "1 2\n2 1\n1 2\n1 2\n");
+        assert!(cfg.functions[0].from_ssa().unwrap());
+        let bril_txt = cfg.to_text();
+        println!("bril_txt: {bril_txt}");
+        assert!(no_phis(&cfg));
+        assert!(bril_txt.contains("br c .split0 .done;"));
+        assert!(bril_txt.contains("x.tmp0: int = id x;"));
+        assert_eq!(cfg.interpret(&[]).unwrap(), expected);
+        assert!(!cfg.functions[0].from_ssa().unwrap());
+    }
+
+    #[test]
+    fn from_ssa_lost_copy() {
+        // x is still read after the loop, so the copy for the back edge must
+        // not end up on the exit edge
+        let bril_text = r#"@main(n: int) {
+.top:
+  x0: int = const 1;
+  one: int = const 1;
+.loop:
+  x: int = phi x0 x1 .top .loop;
+  x1: int = add x one;
+  c: bool = lt x1 n;
+  br c .loop .done;
+.done:
+  print x;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        let n = [Literal::Number(5)];
+        let expected = cfg.interpret(&n).unwrap();
+        assert_eq!(expected, "4\n");
+        cfg.from_ssa().unwrap();
+        println!("bril_txt: {}", cfg.to_text());
+        assert!(no_phis(&cfg));
+        assert_eq!(cfg.interpret(&n).unwrap(), expected);
+        BrilCFG::from_text(&cfg.to_text()).unwrap();
+    }
+
+    #[test]
+    fn ssa_round_trip() {
+        let bril_text = r#"@main(n: int) {
+  a: int = const 0;
+  b: int = const 1;
+  i: int = const 0;
+  one: int = const 1;
+.loop:
+  c: bool = lt i n;
+  br c .body .done;
+.body:
+  t: int = add a b;
+  a: int = id b;
+  b: int = id t;
+  i: int = add i one;
+  odd: bool = call @odd i;
+  br odd .loop .even;
+.even:
+  print a;
+  jmp .loop;
+.done:
+  print a b;
+}
+
+@odd(x: int): bool {
+  two: int = const 2;
+  h: int = div x two;
+  h: int = mul h two;
+  r: bool = eq h x;
+  r: bool = not r;
+  ret r;
+}"#;
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        let n = [Literal::Number(10)];
+        let expected = cfg.interpret(&n).unwrap();
+        cfg.to_ssa().unwrap();
+        assert_eq!(cfg.interpret(&n).unwrap(), expected);
+        cfg.from_ssa().unwrap();
+        assert!(no_phis(&cfg));
+        assert_eq!(cfg.interpret(&n).unwrap(), expected);
+    }
+
+    #[test]
+    fn ssa_round_trip_random() {
+        let mut rng = Rng::new(11);
+        let mut finished = 0;
+        for _ in 0..200 {
+            let n = rng.below(8) + 1;
+            let mut text = String::new();
+            for (k, line) in random_function(&mut rng, n).lines().enumerate() {
+                let line = match (line, k % 5) {
+                    ("@main(c: bool) {", _) => "@main(c: bool) {\n  x: int = const 1;\n  y: int = const 2;".to_string(),
+                    ("  print c;", 0) => "  t: int = id x;\n  x: int = id y;\n  y: int = id t;".to_string(),
+                    ("  print c;", 1) => "  x: int = add x y;\n  c: bool = lt x y;".to_string(),
+                    ("  print c;", 2) => "  y: int = mul y x;\n  c: bool = gt y x;".to_string(),
+                    ("  print c;", _) => "  print x y;".to_string(),
+                    (line, _) => line.to_string(),
+                };
+                text.push_str(&line);
+                text.push('\n');
+            }
+            let mut cfg = BrilCFG::from_text(&text).unwrap();
+            let args = [Literal::Bool(true)];
+            let mut interp = crate::interp::Interpreter::new(&cfg).with_max_steps(200);
+            if interp.run_main(&args).is_err() {
+                continue;
+            }
+            let expected = interp.output;
+            finished += 1;
+
+            cfg.to_ssa().unwrap();
+            assert_eq!(cfg.interpret(&args).unwrap(), expected, "{text}\n{}", cfg.to_text());
+            cfg.from_ssa().unwrap();
+            assert!(no_phis(&cfg));
+            assert_eq!(cfg.interpret(&args).unwrap(), expected, "{text}\n{}", cfg.to_text());
+        }
+        assert!(finished > 50);
+    }
+
+    #[test]
+    fn verify_ssa() {
+        let valid = r#"@main(c: bool) {
+.top:
+  one: int = const 1;
+  br c .then .join;
+.then:
+  two: int = add one one;
+.join:
+  x: int = phi one two .top .then;
+  y: int = phi __undefined two .top .then;
+  print x;
+}"#;
+        let cfg = BrilCFG::from_text(valid).unwrap();
+        verify(&cfg.functions[0]).unwrap();
+
+        let invalid = [
+            ("@main { a: int = const 1; a: int = const 2; }", "`a` is defined more than once"),
+            ("@main(a: int) { a: int = const 1; }", "`a` is defined more than once"),
+            ("@main { print a; }", "`a` is used in .main but never defined"),
+            ("@main { print a; a: int = const 1; }", "the definition of `a` does not dominate its use in .main"),
+            (
+                "@main(c: bool) { br c .l .r; .l: a: int = const 1; .r: print a; }",
+                "the definition of `a` does not dominate its use in .r",
+            ),
+            (
+                "@main(c: bool) { .t: br c .l .r; .l: a: int = const 1; .r: x: int = phi c c .t .t; }",
+                "phi labels don't match the predecessors [.t, .l]",
+            ),
+            (
+                "@main(c: bool) { .t: br c .l .r; .l: a: int = const 1; .r: x: int = phi c .t; }",
+                "phi labels don't match the predecessors",
+            ),
+            (
+                "@main(c: bool) { .t: br c .l .r; .l: jmp .r; .r: x: int = phi a c .t .l; a: int = const 1; }",
+                "the definition of `a` does not reach the end of .t",
+            ),
+            (
+                "@main(c: bool) { .t: jmp .r; .r: print c; x: bool = phi c .t; }",
+                "phi after other instructions in .r",
+            ),
+        ];
+        for (text, msg) in invalid {
+            let cfg = BrilCFG::from_text(text).unwrap();
+            match verify(&cfg.functions[0]) {
+                Err(e @ Error::InvalidSsa(_)) => assert!(e.to_string().contains(msg), "{text}: {e}"),
+                result => panic!("{text}: {result:?}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,447 @@
+// unrolling of loops that run a number of times known at compile time
+use std::collections::HashMap;
+
+use crate::{
+    cfg::{BlockId, BrilCFG, FunctionCFG},
+    error::Result,
+    loops::LoopForest,
+    parser::{Instr, Literal, Opcode, Type},
+    pass::{AnalysisCache, Granularity, Pass},
+    ssa::{fresh_var, is_phi, var_names},
+};
+
+// instructions a fully unrolled loop may grow to
+const DEFAULT_MAX_SIZE: usize = 128;
+const DEFAULT_FACTOR: usize = 4;
+
+pub struct Unroll {
+    // size in instructions up to which a loop gets unrolled fully
+    pub max_size: usize,
+    // copies of the body in a partially unrolled loop
+    pub factor: usize,
+}
+
+impl Default for Unroll {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_SIZE,
+            factor: DEFAULT_FACTOR,
+        }
+    }
+}
+
+impl Pass for Unroll {
+    fn name(&self) -> &'static str {
+        "unroll"
+    }
+
+    fn granularity(&self) -> Granularity {
+        Granularity::Function
+    }
+
+    fn run_on_function(&mut self, func: &mut FunctionCFG, _cache: &mut AnalysisCache) -> Result<bool> {
+        func.unroll(self.max_size, self.factor)
+    }
+}
+
+impl BrilCFG {
+    pub fn unroll(&mut self, max_size: usize, factor: usize) -> Result<()> {
+        for func in self.functions.iter_mut() {
+            func.unroll(max_size, factor)?;
+        }
+        Ok(())
+    }
+}
+
+fn jmp(label: &str) -> Instr {
+    Instr::Instruction {
+        op: Opcode::jmp,
+        dest: None,
+        typ: None,
+        args: None,
+        funcs: None,
+        labels: Some(vec![label.to_string()]),
+        value: None,
+    }
+}
+
+impl FunctionCFG {
+    // unroll the counted loops, the ones that become at most `max_size`
+    // instructions entirely and the rest `factor` times. works out of ssa
+    // form. returns whether anything changed
+    pub fn unroll(&mut self, max_size: usize, factor: usize) -> Result<bool> {
+        if self.blocks.iter().flat_map(|block| block.instrs()).any(is_phi) {
+            return Ok(false);
+        }
+        let forest = LoopForest::compute(self);
+        let headers = forest
+            .postorder()
+            .into_iter()
+            .map(|id| self.blocks[forest.get(id).header].name.clone())
+            .collect::<Vec<_>>();
+        let mut changed = false;
+        for header in headers {
+            changed |= self.unroll_loop(&header, max_size, factor)?;
+        }
+        Ok(changed)
+    }
+
+    fn unroll_loop(&mut self, header: &str, max_size: usize, factor: usize) -> Result<bool> {
+        let forest = LoopForest::compute(self);
+        let Some(header) = self.block_id(header) else {
+            return Ok(false);
+        };
+        let Some(id) = forest.innermost(header).filter(|_| header != self.entry()) else {
+            return Ok(false);
+        };
+        let Some(counted) = self.counted_loop(&forest, id) else {
+            return Ok(false);
+        };
+        let l = forest.get(id);
+        // only dead code can jump into the middle of a loop, but it has to
+        // keep its labels
+        let entered = |block: &BlockId| self.blocks[*block].preds().iter().any(|pred| !l.contains(*pred));
+        if l.blocks.iter().filter(|block| **block != header).any(entered) {
+            return Ok(false);
+        }
+        let size = l.blocks.iter().map(|block| self.blocks[*block].instrs().len()).sum::<usize>();
+        let passes = counted.trips as usize + 1;
+        let outside_preds = self.blocks[header]
+            .preds()
+            .iter()
+            .copied()
+            .filter(|pred| !l.contains(*pred))
+            .collect::<Vec<_>>();
+
+        if size.saturating_mul(passes) <= max_size {
+            // one copy per pass, each one's exit test decided already. the
+            // last pass of a loop tested at the top only runs the header
+            let mut next = None;
+            for pass in (0..passes).rev() {
+                let last = pass + 1 == passes;
+                let blocks = if last && counted.exiting == header { vec![header] } else { l.blocks.clone() };
+                let exit_to = if last { &counted.leave } else { &counted.stay };
+                next = Some(self.copy_loop(&blocks, header, counted.exiting, exit_to, next.as_deref()));
+            }
+            let first = self.block_id(&next.unwrap()).unwrap();
+            for pred in outside_preds {
+                self.redirect_edge(pred, header, first);
+            }
+            // nothing jumps into the original loop anymore
+            let mut index = 0;
+            self.blocks.retain(|_| {
+                index += 1;
+                !l.contains(index - 1)
+            });
+            self.resolve_cfg()?;
+            return Ok(true);
+        }
+
+        let factor = factor as i64;
+        if factor < 2 || counted.trips < factor {
+            return Ok(false);
+        }
+        // the unrolled loop runs whole multiples of `factor` passes, then the
+        // original loop is the remainder loop. the variable doesn't wrap
+        // around, so it takes each value once and comparing it to its value
+        // after these passes is the check
+        let passes = counted.trips / factor * factor;
+        let limit = counted.init + counted.step * passes;
+        let mut names = var_names(self);
+        let limit_var = fresh_var(&mut names, &counted.var);
+        let done = fresh_var(&mut names, &counted.var);
+        let check = self.fresh_block_name("unroll");
+        let mut next = check.clone();
+        for _ in 0..factor {
+            next = self.copy_loop(&l.blocks, header, counted.exiting, &counted.stay, Some(&next));
+        }
+        let check = self.append_block(
+            check,
+            vec![
+                Instr::new_const_instr(&limit_var, Literal::Number(limit), Type::int),
+                Instr::Instruction {
+                    op: Opcode::eq,
+                    dest: Some(done.clone()),
+                    typ: Some(Type::bool),
+                    args: Some(vec![counted.var.clone(), limit_var]),
+                    funcs: None,
+                    labels: None,
+                    value: None,
+                },
+                Instr::Instruction {
+                    op: Opcode::br,
+                    dest: None,
+                    typ: None,
+                    args: Some(vec![done]),
+                    funcs: None,
+                    labels: Some(vec![self.blocks[header].name.clone(), next]),
+                    value: None,
+                },
+            ],
+        );
+        for pred in outside_preds {
+            self.redirect_edge(pred, header, check);
+        }
+        self.resolve_cfg()?;
+        Ok(true)
+    }
+
+    // append a copy of `blocks` of the loop with `header` for one pass, in
+    // which the exit test in `exiting` always goes to `exit_to`. the back
+    // edges go to `next`. returns the name of the copied header
+    fn copy_loop(&mut self, blocks: &[BlockId], header: BlockId, exiting: BlockId, exit_to: &str, next: Option<&str>) -> String {
+        let names = blocks
+            .iter()
+            .map(|block| (self.blocks[*block].name.clone(), self.fresh_block_name("unroll")))
+            .collect::<HashMap<_, _>>();
+        let header_name = self.blocks[header].name.clone();
+        let target = |label: &str| {
+            if label == header_name {
+                next.unwrap_or(label).to_string()
+            } else {
+                names.get(label).cloned().unwrap_or_else(|| label.to_string())
+            }
+        };
+        for block in blocks {
+            let mut instrs = self.blocks[*block].instrs().to_vec();
+            let succs = self.blocks[*block].succs();
+            match instrs.last_mut() {
+                Some(exit) if *block == exiting => *exit = jmp(&target(exit_to)),
+                Some(Instr::Instruction { op: Opcode::jmp | Opcode::br, labels: Some(labels), .. }) => {
+                    for label in labels.iter_mut() {
+                        *label = target(label);
+                    }
+                }
+                Some(Instr::Instruction { op: Opcode::ret, .. }) => {}
+                _ => match succs.first() {
+                    Some(succ) => instrs.push(jmp(&target(self.blocks[*succ].name()))),
+                    None => instrs.push(Instr::Instruction {
+                        op: Opcode::ret,
+                        dest: None,
+                        typ: None,
+                        args: None,
+                        funcs: None,
+                        labels: None,
+                        value: None,
+                    }),
+                },
+            }
+            let name = names[&self.blocks[*block].name].clone();
+            self.append_block(name, instrs);
+        }
+        names[&header_name].clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::induction::CountedLoop;
+
+    fn counted(bril_text: &str) -> Option<CountedLoop> {
+        let cfg = BrilCFG::from_text(bril_text).unwrap();
+        let main = cfg.function("main").unwrap();
+        let forest = LoopForest::compute(main);
+        main.counted_loop(&forest, forest.postorder()[0])
+    }
+
+    const TOP_TESTED: &str = r#"@main {
+  i: int = const 0;
+  n: int = const 5;
+  one: int = const 1;
+.loop:
+  c: bool = lt i n;
+  br c .body .done;
+.body:
+  print i;
+  i: int = add i one;
+  jmp .loop;
+.done:
+  print n;
+}"#;
+
+    const BOTTOM_TESTED: &str = r#"@main {
+  i: int = const 10;
+  zero: int = const 0;
+.loop:
+  print i;
+  three: int = const 3;
+  i: int = sub i three;
+  c: bool = gt i zero;
+  br c .loop .done;
+.done:
+  print zero;
+}"#;
+
+    #[test]
+    fn counted_loops() {
+        let top = counted(TOP_TESTED).unwrap();
+        assert_eq!((top.var.as_str(), top.init, top.step, top.trips), ("i", 0, 1, 5));
+        assert_eq!((top.stay.as_str(), top.leave.as_str()), ("body", "done"));
+        // sees 7, 4, 1 and leaves at -2
+        let bottom = counted(BOTTOM_TESTED).unwrap();
+        assert_eq!((bottom.init, bottom.step, bottom.trips), (10, -3, 3));
+        assert_eq!((bottom.stay.as_str(), bottom.leave.as_str()), ("loop", "done"));
+
+        // the bound is an argument
+        assert!(counted(&TOP_TESTED.replace("@main {\n  i: int = const 0;\n  n: int = const 5;", "@main(n: int) {\n  i: int = const 0;")).is_none());
+        // the update doesn't run every pass
+        let conditional = TOP_TESTED.replace("  print i;\n", "  br c .inc .loop;\n.inc:\n");
+        assert!(counted(&conditional).is_none());
+        // never leaves
+        assert!(counted(&TOP_TESTED.replace("i: int = add i one;", "i: int = sub i one;")).is_none());
+        // leaves only after wrapping around
+        let wraps = TOP_TESTED.replace("c: bool = lt i n;", "c: bool = ge i n;").replace("const 5", "const -5");
+        assert!(counted(&wraps).is_none());
+
+        // the count doesn't depend on simulating the loop
+        let long = counted(&TOP_TESTED.replace("const 5", "const 70000")).unwrap();
+        assert_eq!(long.trips, 70000);
+        let near_max = TOP_TESTED.replace("const 0;", "const 9223372036854775000;").replace("const 5", "const 9223372036854775807");
+        assert_eq!(counted(&near_max).unwrap().trips, 807);
+        // `n > i` and `i <= n` are the same tests as `i < n` and `i < n + 1`
+        let swapped = TOP_TESTED.replace("c: bool = lt i n;", "c: bool = gt n i;");
+        assert_eq!(counted(&swapped).unwrap().trips, 5);
+        let le = TOP_TESTED.replace("c: bool = lt i n;", "c: bool = le i n;");
+        assert_eq!(counted(&le).unwrap().trips, 6);
+        // stays while not equal, leaves on `i == 5`
+        let ne = TOP_TESTED.replace("c: bool = lt i n;", "c: bool = eq i n;").replace("br c .body .done;", "br c .done .body;");
+        assert_eq!(counted(&ne).unwrap().trips, 5);
+        // misses the bound
+        assert!(counted(&ne.replace("const 5", "const -5")).is_none());
+        // the bottom tested loop sees 7, 4, 1 and -2 with a step of 3
+        let bottom = BOTTOM_TESTED.replace("c: bool = gt i zero;", "c: bool = le zero i;");
+        assert_eq!(counted(&bottom).unwrap().trips, 3);
+    }
+
+    fn assert_same_output(before: &BrilCFG, after: &BrilCFG) {
+        assert_eq!(before.interpret(&[]).unwrap(), after.interpret(&[]).unwrap());
+    }
+
+    #[test]
+    fn full_unroll() {
+        for bril_text in [TOP_TESTED, BOTTOM_TESTED] {
+            let original = BrilCFG::from_text(bril_text).unwrap();
+            let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+            assert!(cfg.functions[0].unroll(DEFAULT_MAX_SIZE, DEFAULT_FACTOR).unwrap());
+            println!("bril_txt: {}", cfg.to_text());
+            assert_same_output(&original, &cfg);
+            assert!(LoopForest::compute(&cfg.functions[0]).loops().is_empty());
+            assert!(!cfg.to_text().contains(".loop:"));
+        }
+
+        // new labels stay clear of the ones in the program and of the names
+        // of unlabelled blocks
+        let bril_text = r#"@main {
+  i: int = const 0;
+  two: int = const 2;
+  one: int = const 1;
+.unroll1:
+  c: bool = lt i two;
+  br c .body .unroll0;
+.body:
+  print i;
+  jmp .skip;
+.skip:
+  i: int = add i one;
+  jmp .unroll1;
+.unroll0:
+  print one;
+  ret;
+  print two;
+}"#;
+        let original = BrilCFG::from_text(bril_text).unwrap();
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        cfg.unroll(DEFAULT_MAX_SIZE, DEFAULT_FACTOR).unwrap();
+        let bril_txt = cfg.to_text();
+        println!("bril_txt: {bril_txt}");
+        assert_same_output(&original, &cfg);
+        assert!(bril_txt.contains(".unroll0:\n  print one;"));
+        assert_eq!(bril_txt.matches(".unroll0:").count(), 1);
+        assert!(!bril_txt.contains(".unroll1:"));
+        assert!(bril_txt.contains(".tmp0:"));
+    }
+
+    #[test]
+    fn partial_unroll() {
+        // eight passes run unrolled, the remainder loop starts from `limit`
+        let cases = [(TOP_TESTED.replace("const 5", "const 10"), 10, 8), (BOTTOM_TESTED.replace("const 10", "const 31"), 10, 7)];
+        for (bril_text, trips, limit) in cases {
+            let original = BrilCFG::from_text(&bril_text).unwrap();
+            let mut cfg = BrilCFG::from_text(&bril_text).unwrap();
+            let func = &mut cfg.functions[0];
+            let forest = LoopForest::compute(func);
+            assert_eq!(func.counted_loop(&forest, 0).unwrap().trips, trips);
+            assert!(func.unroll(8, 4).unwrap());
+            let bril_txt = cfg.to_text();
+            println!("bril_txt: {bril_txt}");
+            assert_same_output(&original, &cfg);
+            // the unrolled loop and the remainder loop
+            let forest = LoopForest::compute(&cfg.functions[0]);
+            assert_eq!(forest.loops().len(), 2);
+            assert!(bril_txt.contains(&format!("i.tmp0: int = const {limit};\n  i.tmp1: bool = eq i i.tmp0;")));
+            // no further unrolling, neither loop has a known count anymore
+            assert!(!cfg.functions[0].unroll(8, 4).unwrap());
+        }
+
+        // too many passes to count by running the loop
+        let bril_text = r#"@main {
+  i: int = const 0;
+  n: int = const 70001;
+  one: int = const 1;
+  s: int = const 0;
+.loop:
+  s: int = add s i;
+  i: int = add i one;
+  c: bool = lt i n;
+  br c .loop .done;
+.done:
+  print s i;
+}"#;
+        let original = BrilCFG::from_text(bril_text).unwrap();
+        let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+        assert!(cfg.functions[0].unroll(DEFAULT_MAX_SIZE, DEFAULT_FACTOR).unwrap());
+        assert_same_output(&original, &cfg);
+        assert!(cfg.to_text().contains("i.tmp0: int = const 70000;"));
+
+        // fewer passes than copies
+        let mut cfg = BrilCFG::from_text(TOP_TESTED).unwrap();
+        assert!(!cfg.functions[0].unroll(8, 8).unwrap());
+    }
+
+    #[test]
+    fn unroll_nested() {
+        let bril_text = r#"@main {
+  one: int = const 1;
+  three: int = const 3;
+  i: int = const 0;
+  sum: int = const 0;
+.outer:
+  j: int = const 0;
+.inner:
+  x: int = mul i j;
+  sum: int = add sum x;
+  j: int = add j one;
+  d: bool = lt j three;
+  br d .inner .inner_done;
+.inner_done:
+  print sum;
+  i: int = add i one;
+  c: bool = le i three;
+  br c .outer .done;
+.done:
+  print i j;
+}"#;
+        let original = BrilCFG::from_text(bril_text).unwrap();
+        for (max_size, factor) in [(DEFAULT_MAX_SIZE, DEFAULT_FACTOR), (20, 2), (0, 3)] {
+            let mut cfg = BrilCFG::from_text(bril_text).unwrap();
+            cfg.unroll(max_size, factor).unwrap();
+            println!("bril_txt: {}", cfg.to_text());
+            assert_same_output(&original, &cfg);
+            // the inner loop goes first, which leaves the outer one small enough
+            if max_size == DEFAULT_MAX_SIZE {
+                assert!(LoopForest::compute(&cfg.functions[0]).loops().is_empty());
+            }
+        }
+    }
+}